@@ -7,6 +7,8 @@ and writing ones use `wa` (write-aliases)
 prelude!();
 use crate::file;
 use crate::config::FullConfig;
+use crate::shell::Shell;
+use crate::backend::Offload;
 use std::io::{self, BufReader, BufRead, BufWriter, Write};
 use std::{fs, path::PathBuf, fs::File};
 
@@ -66,11 +68,11 @@ fn find_prefix_line(buf :BufReader<File>, path :PathBuf) -> ra::Result<(String,
 	Ok((line, path))
 }
 
-/// Returns the list of old aliases read from the aliases file
-pub fn read_old_aliases () -> ra::Result<Vec<String>> {
+/// Returns the list of old aliases read from the aliases file of the shell
+pub fn read_old_aliases (sh :Shell) -> ra::Result<Vec<String>> {
 	use ra::Error::*;
 	
-	let path = terror! { file::find_aliases_file() => |_| FindFileF };
+	let path = terror! { file::find_aliases_file(sh) => |_| FindFileF };
 	let alias_file = terror! { File::open(&path) => |e| ra::IoF(path, e) };
 	let alias_buf = io::BufReader::new(alias_file);
 	
@@ -83,13 +85,29 @@ pub fn read_old_aliases () -> ra::Result<Vec<String>> {
 
 // ---
 
-/// Write the alias file contents
-fn write_aliases_text (file :&mut wa::Buf, config :&FullConfig, path :PathBuf)
+/// The words to put before a command to offload it, with a trailing space if not empty
+fn offload_prefix (offload :&Offload, sh :Shell) -> String {
+	let mut words :Vec<String> = Vec::new();
+	if !offload.unset.is_empty() {
+		words.push("env".to_string());
+		words.extend(offload.unset.iter().map(|v| format!("-u {}", v)));
+	}
+	words.extend(offload.set.iter().map(|(k, v)| format!("{}={}", k, sh.word(v))));
+	words.extend(offload.wrapper.iter().map(|v| sh.word(v)));
+	
+	words.into_iter().map(|v| v + " ").collect()
+}
+
+/** Write the alias file contents
+
+The alias syntax is the same for bash and zsh. fish has no aliases, so we write
+wrapper functions instead
+*/
+fn write_aliases_text (file :&mut wa::Buf, aliases :&[(&str, Offload)], sh :Shell, path :PathBuf)
 					   -> wa::Result<PathBuf>
 {
 	#![allow(clippy::string_lit_as_bytes)]
 	use wa::Error::*;
-	use crate::util::shell_escape;
 
 	/// Our writer function
 	macro_rules! w {
@@ -98,7 +116,10 @@ fn write_aliases_text (file :&mut wa::Buf, config :&FullConfig, path :PathBuf)
 		}
 	}
 
-	let alias_json = terror! { serde_json::to_string(&config.alias) => |e| ToJsonF(path, e) };
+	let commands :Vec<&str> = aliases.iter().map(|(c, _)| *c).collect();
+	let alias_json = terror! {
+		serde_json::to_string(&commands) => |e| ToJsonF(path, e)
+	};
 
 	// Start writing
 	w!("# Generated by switchable, modifications will be overwritten\n");
@@ -108,9 +129,20 @@ fn write_aliases_text (file :&mut wa::Buf, config :&FullConfig, path :PathBuf)
 	w!(format!("{}{}\n", JSON_PREFIX, alias_json));
 	w!("\n");
 
-	for cmd in &config.alias {
-		let cmd = shell_escape(cmd);
-		w!(format!("alias {cmd}='DRI_PRIME=1 '{cmd}\n", cmd=cmd));
+	for (command, offload) in aliases {
+		// eg. `DRI_PRIME=1` or `optirun`
+		let prefix = offload_prefix(offload, sh);
+		// In fish functions, we need `command` to avoid recursion unless `env` or a wrapper runs it
+		let fish_command = if offload.unset.is_empty() && offload.wrapper.is_empty() { "command " } else { "" };
+		
+		let cmd = sh.word(command);
+		if sh.is_posix() {
+			let body = format!("{}{}", prefix, cmd);
+			w!(format!("alias {}={}\n", cmd, sh.escape(&body)));
+		} else {
+			w!(format!("function {cmd} --wraps {cmd}; {prefix}{command}{cmd} $argv; end\n",
+				cmd=cmd, prefix=prefix, command=fish_command));
+		}
 	}
 
 	w!("# End of file");
@@ -133,32 +165,54 @@ pub mod wa {
 		ToJsonF(PathBuf, #[source] serde_json::Error),
 		#[error("Aliases path {0:?} is not valid utf8")]
 		PathNotUtf8(PathBuf),
+		#[error("Failed to find the GPU for alias {0:?}")]
+		DriverF(String, #[source] crate::config::Error),
 	}
 
 	pub type Result<T> = result::Result<T, self::Error>;
 	pub type Buf = io::BufWriter<File>;
 }
 
-/// Writes the aliases file if it can. Aborts as soon as it encounters an error
-pub fn write_aliases (config :&FullConfig) -> wa::Result<String> {
+/// What `write_aliases` did
+pub struct Written {
+	pub path :String,
+	/// Why the aliases that were left out couldn't be written, as `DriverF` errors
+	pub skipped :Vec<wa::Error>,
+}
+
+/** Writes the aliases file for the shell if it can. The aliases whose GPU can't be found are
+left out, and the others are written. Aborts as soon as it encounters another error
+*/
+pub fn write_aliases (config :&FullConfig, sh :Shell) -> wa::Result<Written> {
 	use wa::Error::*;
 	
+	// Resolve the drivers before opening the file, which truncates it
+	let mut aliases = Vec::new();
+	let mut skipped = Vec::new();
+	for alias in &config.alias {
+		let driver = alias.driver().unwrap_or(&config.driver);
+		match config.driver_offload(driver) {
+			Ok(offload) => aliases.push((alias.command(), offload)),
+			Err(e) => skipped.push(DriverF(alias.command().to_string(), e)),
+		}
+	}
+	
 	// Find and open file
-	let path = terror! { file::find_aliases_file() => |_| FindFileF };
+	let path = terror! { file::find_aliases_file(sh) => |_| FindFileF };
 	let file = terror! {
 		fs::OpenOptions::new()
-		.write(true).create(true)
+		.write(true).create(true).truncate(true)
 		.open(&path) => |e| WriteFileF(path, e)
 	};
 	let mut file = BufWriter::new(file);
 	
 	// Write text
-	let path = terror! { write_aliases_text(&mut file, &config, path) };
+	let path = terror! { write_aliases_text(&mut file, &aliases, sh, path) };
 	terror! { file.flush() => |e| WriteFileF(path, e) };
 
 	// Return path as valid String
 	let path = terror! {
 		path.into_os_string().into_string() => |e| PathNotUtf8(PathBuf::from(e))
 	};
-	Ok(path)
+	Ok(Written { path, skipped })
 }
@@ -1,32 +1,186 @@
 //! Application related functions, not the entry point
 prelude!();
 use crate::exitcode::{self, ExitCode};
-use crate::{config, alias, util};
+use crate::{config, alias, util, cmdline, prefix};
+use crate::cmdline::SimpleCommand;
+use crate::shell::Shell;
 use std::error::Error;
-use config::FullConfig;
+use config::{FullConfig, Driver};
+use crate::rule::{self, Rule, Strategy, Precedence};
+use crate::matcher::{Matcher, Field, Origin};
+use crate::backend::Offload;
+use crate::session::Session;
+use crate::util::Lookup;
 use regex::Regex;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 
-/// Test if the command matches any regex. The metadata is whether a regex failed to compile
-pub fn matches_command(config :&FullConfig, s :&str) -> Metadata<bool, bool> {
-	let mut failed = false;
+/// A simple command of the command line, as seen by the rules
+pub struct Command {
+	pub command :SimpleCommand,
+	/// The words without the leading assignments and prefix commands
+	pub argv :Vec<String>,
+	/// Whether the top-level `exclude` key matches it, so that no rule does
+	pub excluded :bool,
+}
+
+/// What a rule makes of the command line
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Outcome {
+	/// It matches the command at this index
+	Matched(usize),
+	/// It would match the command at this index, but its `exclude` key vetoes it
+	Excluded(usize),
+	NoMatch,
+	Disabled,
+}
+
+/// A rule matching a command line
+pub struct Match<'a> {
+	pub rule :&'a Rule,
+	/// The simple command of the command line that matched
+	pub command :SimpleCommand,
+}
+
+/// The rules matching a command line, and the one that is used
+pub struct Matches<'a> {
+	/// The simple commands of the command line
+	pub commands :Vec<Command>,
+	/// Every rule with its outcome, in the order of the configuration
+	pub outcomes :Vec<(&'a Rule, Outcome)>,
+	/// The enabled rules matching a command, in the order of the configuration
+	pub candidates :Vec<Match<'a>>,
+	/// Index of the winner in `candidates`
+	winner :Option<usize>,
+}
+
+impl<'a> Matches<'a> {
+	/// Picks the winner among the candidates: the highest `priority`, then the rules using the
+	/// integrated GPU, then the first or last one in the configuration depending on `strategy`
+	fn pick_winner (config :&FullConfig, candidates :&[Match]) -> Option<usize> {
+		let key = |m :&Match| (m.rule.priority, m.rule.driver(&config.driver).is_integrated());
+		
+		let mut winner :Option<usize> = None;
+		for (i, m) in candidates.iter().enumerate() {
+			let better = match winner {
+				None => true,
+				Some(w) => match key(m).cmp(&key(&candidates[w])) {
+					Ordering::Greater => true,
+					Ordering::Equal => config.strategy == Strategy::LastMatch,
+					Ordering::Less => false,
+				},
+			};
+			if better {
+				winner = Some(i);
+			}
+		}
+		winner
+	}
+	
+	/// The rule to use, if any
+	pub fn winner (&self) -> Option<&Match<'a>> {
+		self.winner.map(|i| &self.candidates[i])
+	}
+	
+	/// What to change to run the command line in the environment `env`, which has the state
+	/// of the shell, if a rule matched
+	pub fn offload (&self, config :&FullConfig, env :&BTreeMap<String, String>) -> Option<config::Result<Offload>> {
+		let session = Session::from_env(env);
+		self.winner().map(|m| {
+			let driver = session.driver(m.rule, &config.driver);
+			rule_offload(config, m.rule, driver).map(|mut offload| {
+				if m.rule.precedence(config.precedence) == Precedence::User {
+					offload.keep_env(env);
+				}
+				offload
+			})
+		})
+	}
+}
+
+/// What the rule at index `r` makes of the command, whose prefixes are stripped:
+/// `Matched(0)`, `Excluded(0)` or `NoMatch`. `hits` are the patterns matching its program
+fn rule_outcome (matcher :&Matcher, lookup :&Lookup, r :usize, rule :&Rule, argv :&[String], hits :&[Origin]) -> Outcome {
+	let program = tear! { argv.first() => |_| Outcome::NoMatch };
+	let hit = |field :Field| hits.iter().any(|o| o.rule == Some(r) && o.field == field);
+	
+	// Patterns on the program, then the program and arguments
+	let matches = hit(Field::Match)
+		|| rule.program.as_ref().is_some_and(|expected| rule::is_program(expected, program, lookup)
+			&& rule.args.len() < argv.len()
+			&& argv[1..].iter().take(rule.args.len()).enumerate().all(|(i, a)| matcher.arg_matches(r, i, a)));
+	
+	match matches {
+		false => Outcome::NoMatch,
+		true if hit(Field::Exclude) => Outcome::Excluded(0),
+		true => Outcome::Matched(0),
+	}
+}
+
+/** Returns what each rule makes of the simple commands of the command line, and the winner
+among the matching ones. Leading assignments and prefix commands like `sudo` are skipped, and
+the commands excluded by the `exclude` key are ignored
+
+The programs are looked up with `lookup`, for the patterns and rules on their path.
+Invalid patterns never match, see `Matcher::invalid`.
+*/
+pub fn matches_command<'a> (config :&'a FullConfig, matcher :&Matcher, lookup :&Lookup, line :&str) -> Matches<'a> {
+	let mut hits = Vec::new();
+	let commands :Vec<Command> = cmdline::split(line).into_iter()
+		.map(|command| {
+			let argv = prefix::strip(&command.words, &config.prefix).to_vec();
+			let h = argv.first().map(|p| matcher.program_matches(p, lookup)).unwrap_or_default();
+			let excluded = h.iter().any(|o| o.rule.is_none());
+			hits.push(h);
+			Command { command, argv, excluded }
+		})
+		.collect();
+	
+	// Each rule with the first command it matches
+	let mut outcomes = Vec::new();
+	let mut candidates = Vec::new();
+	for (r, rule) in config.rules.iter().enumerate() {
+		let mut outcome = if rule.enabled { Outcome::NoMatch } else { Outcome::Disabled };
+		let enabled_commands = commands.iter().zip(&hits).enumerate()
+			.filter(|(_, (c, _))| rule.enabled && !c.excluded);
+		for (i, (c, h)) in enabled_commands {
+			match rule_outcome(matcher, lookup, r, rule, &c.argv, h) {
+				Outcome::Matched(_) => {
+					outcome = Outcome::Matched(i);
+					candidates.push(Match { rule, command: c.command.clone() });
+					break;
+				},
+				Outcome::Excluded(_) if outcome == Outcome::NoMatch => outcome = Outcome::Excluded(i),
+				_ => {},
+			}
+		}
+		outcomes.push((rule, outcome));
+	}
 	
-	// Find it
-	let matched = config.match_.iter()
-		.any(|e| -> bool {
-			let re = tear! { Regex::new(e) => |_| { failed = true; false } }; // TODO improve syntax
-			re.is_match(s)
-		});
+	let winner = Matches::pick_winner(config, &candidates);
+	Matches { commands, outcomes, candidates, winner }
+}
+
+/// What to change to run a command matched by the rule: the backend's offload for the driver,
+/// with the rule's own variables on top
+pub fn rule_offload (config :&FullConfig, rule :&Rule, driver :&Driver) -> config::Result<Offload> {
+	let mut offload = terror! { config.driver_offload(driver) };
 	
-	Metadata(failed, matched)
+	// The rule overrides the backend
+	offload.set.retain(|(k, _)| !rule.env.contains_key(k) && !rule.unset.contains(k));
+	offload.unset.retain(|k| !rule.env.contains_key(k));
+	offload.set.extend(rule.env.iter().map(|(k, v)| (k.clone(), v.clone())));
+	offload.unset.extend(rule.unset.iter().filter(|k| !offload.unset.contains(k)).cloned().collect::<Vec<_>>());
+	Ok(offload)
 }
 
 /** Writes aliases and reload them by printing shell commands if possible
 
 This function writes to the terminal through the text function.
-It reads the configuration file, and write a new aliases file while informing the user
-of the changes
+It reads the configuration file, and write a new aliases file for the shell while informing
+the user of the changes
 */
-pub fn reload_aliases<F :Fn(String) -> String> (text :F) -> Result<String, ExitCode> {
+pub fn reload_aliases<F :Fn(String) -> String> (text :F, sh :Shell) -> Result<String, ExitCode> {
 	/// Our printer
 	macro_rules! pln {
 		($e:expr) => {
@@ -36,19 +190,26 @@ pub fn reload_aliases<F :Fn(String) -> String> (text :F) -> Result<String, ExitC
 
 	// All closures because they depend on the pln which depends on the text function
 
-	let handle_config_failure = |e :config::Error| -> ExitCode {
-		pln!(format!("{}", e));
+	let print_error = |message :String, e :&dyn Error| {
+		pln!(message);
 		if let Some(ee) = e.source() {
 			pln!(format!("{}", ee));
 		}
+	};
+	
+	let handle_config_failure = |e :config::Error| -> ExitCode {
+		print_error(e.to_string(), &e);
 		exitcode::BAD_IO
 	};
 	
 	let alias_write_f = |e :alias::wa::Error| -> ExitCode {
-		pln!(format!("{}", e));
+		print_error(e.to_string(), &e);
 		exitcode::BAD_IO
 	};
 	
+	let alias_skipped = |e :alias::wa::Error| {
+		print_error(format!("{}, it is left out", e), &e);
+	};	
 	let read_alias_f  = |e :alias::ra::Error| {
 		pln!(format!("{}", e))
 	};
@@ -56,14 +217,18 @@ pub fn reload_aliases<F :Fn(String) -> String> (text :F) -> Result<String, ExitC
 	let config = terror! { config::load_config() => handle_config_failure };
 	
 	// Tell the user which commands to unalias
-	let old_aliases = alias::read_old_aliases()
+	let old_aliases = alias::read_old_aliases(sh)
 		.map_err(read_alias_f)
 		.ok();
 	let (to_remove, to_add) = old_aliases
-		.map(|v| util::set_diff(v, config.alias.clone()))
+		.map(|v| util::set_diff(v, config.alias_commands()))
 		.split2();
 	
-	let aliases_file = terror! { alias::write_aliases(&config) => alias_write_f };
+	let written = terror! { alias::write_aliases(&config, sh) => alias_write_f };
+	for e in written.skipped {
+		alias_skipped(e);
+	}
+	let aliases_file = written.path;
 	
 	if let Some(true) = to_add.map(|v| !v.is_empty()) {
 		pln!(format!("New aliases written to '{}'", aliases_file));
@@ -79,7 +244,11 @@ pub fn reload_aliases<F :Fn(String) -> String> (text :F) -> Result<String, ExitC
 				.reduce(|a, b| format!("{} {}", a, b)).unwrap(); // We checked is_empty
 
 			pln!(format!("The following aliases have been removed: {}", aliases_str));
-			pln!("They are still loaded so unalias them by hand");
+			if sh.is_posix() {
+				pln!("They are still loaded so unalias them by hand");
+			} else {
+				pln!("They are still loaded so erase them by hand with `functions --erase`");
+			}
 		}
 	}
 	
@@ -134,3 +303,59 @@ pub fn parse_xrandr () -> pax::Result<Vec<(String, String)>> {
 	
 	Ok(ret)
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// The configuration in TOML, with defaults for what isn't set
+	fn config (toml :&str) -> FullConfig {
+		toml::from_str::<config::RawConfig>(toml).unwrap().set_defaults()
+	}
+
+	/// The name of the rule `pick_winner` picks, when all the rules match
+	fn winner (config :&FullConfig) -> Option<&str> {
+		let command = SimpleCommand { words: vec!["steam".to_string()], text: "steam".to_string() };
+		let candidates :Vec<Match> = config.rules.iter()
+			.map(|rule| Match { rule, command: command.clone() })
+			.collect();
+		Matches::pick_winner(config, &candidates).and_then(|i| candidates[i].rule.name.as_deref())
+	}
+
+	const RULES :&str = r#"
+		[[rule]]
+		name = "a"
+		match = ["steam"]
+		[[rule]]
+		name = "b"
+		match = ["steam"]
+		driver = 0
+		[[rule]]
+		name = "c"
+		match = ["steam"]
+	"#;
+
+	#[test]
+	fn no_candidates () {
+		assert_eq!(winner(&config("")), None);
+	}
+
+	#[test]
+	fn priority_beats_integrated () {
+		let toml = RULES.replacen("name = \"c\"", "name = \"c\"\npriority = 1", 1);
+		assert_eq!(winner(&config(&toml)), Some("c"));
+	}
+
+	#[test]
+	fn integrated_beats_discrete () {
+		assert_eq!(winner(&config(RULES)), Some("b"));
+		assert_eq!(winner(&config(&format!("strategy = \"last-match\"\n{}", RULES))), Some("b"));
+	}
+
+	#[test]
+	fn strategy_breaks_ties () {
+		let toml = RULES.replace("driver = 0", "");
+		assert_eq!(winner(&config(&toml)), Some("a"));
+		assert_eq!(winner(&config(&format!("strategy = \"last-match\"\n{}", toml))), Some("c"));
+	}
+}
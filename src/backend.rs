@@ -0,0 +1,211 @@
+/*! GPU offloading backends
+
+Each backend knows how to make a program render on the chosen GPU: which environment
+variables to set or unset, and which wrapper command to run it with.
+
+`run` and the aliases can use all of it, but the preexec hook can only change the
+environment of the command that is about to run, so wrappers are ignored there.
+*/
+
+use crate::gpu::Gpu;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::path::Path;
+
+/// Mesa's variable, which takes the GPU to use
+pub const DRI_PRIME :&str = "DRI_PRIME";
+/// Mesa's Vulkan device selection layer variable, which takes `vendor:device`
+pub const VK_DEVICE_SELECT :&str = "MESA_VK_DEVICE_SELECT";
+/// Variables used by the proprietary NVIDIA driver for PRIME render offload
+const NVIDIA_VARS :&[(&str, &str)] = &[
+	("__NV_PRIME_RENDER_OFFLOAD", "1"),
+	("__GLX_VENDOR_LIBRARY_NAME", "nvidia"),
+	("__VK_LAYER_NV_optimus", "NVIDIA_only"),
+];
+/// Exists when the proprietary NVIDIA kernel module is loaded
+const NVIDIA_MODULE :&str = "/sys/module/nvidia";
+/// Values of the NVIDIA variables keeping a program off the NVIDIA GPU
+const NVIDIA_OFF_VARS :&[(&str, &str)] = &[
+	("__GLX_VENDOR_LIBRARY_NAME", "mesa"),
+	("__VK_LAYER_NV_optimus", "non_NVIDIA_only"),
+];
+/// Bumblebee's wrapper command
+const OPTIRUN :&str = "optirun";
+
+/// What to change so that a program runs on a GPU
+#[derive(Default, Debug)]
+pub struct Offload {
+	/// Variables to set, with their values
+	pub set :Vec<(String, String)>,
+	/// Variables to unset
+	pub unset :Vec<String>,
+	/// Command and arguments to run the program with, eg. `optirun`
+	pub wrapper :Vec<String>,
+}
+
+impl Offload {
+	/// Offload that only sets variables
+	fn vars<K :ToString, V :ToString> (vars :&[(K, V)]) -> Offload {
+		Offload {
+			set: vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+			..Offload::default()
+		}
+	}
+	
+	/// Leaves out the variables set in the environment, so that they keep their value
+	pub fn keep_env (&mut self, env :&BTreeMap<String, String>) {
+		self.set.retain(|(k, _)| !env.contains_key(k));
+		self.unset.retain(|k| !env.contains_key(k));
+	}
+}
+
+/// A way of offloading rendering to a GPU
+pub trait GpuBackend :Debug {
+	/// Name as written in the config
+	fn name (&self) -> &'static str;
+
+	/// What to change so that a program runs on the GPU, whose meaning depends on the backend
+	fn offload (&self, gpu :&str) -> Offload;
+	
+	/// What to change so that a program runs on the integrated GPU, even if the discrete one
+	/// is the default
+	fn integrated (&self, gpu :&str) -> Offload {
+		self.offload(gpu)
+	}
+
+	/// How to refer to a detected GPU in `offload`
+	fn gpu_id (&self, gpu :&Gpu) -> String {
+		gpu.pci_tag()
+	}
+
+	/// How to refer to a GPU by its index as understood by `DRI_PRIME`, if the backend takes
+	/// indices. Otherwise the GPU is looked up and referred to with `gpu_id`
+	fn index (&self, _i :u32) -> Option<String> {
+		None
+	}
+}
+
+/// Mesa drivers, with `DRI_PRIME`
+#[derive(Debug)]
+pub struct Mesa;
+
+impl GpuBackend for Mesa {
+	fn name (&self) -> &'static str { "mesa" }
+
+	fn offload (&self, gpu :&str) -> Offload {
+		Offload::vars(&[(DRI_PRIME, gpu)])
+	}
+
+	fn index (&self, i :u32) -> Option<String> {
+		Some(i.to_string())
+	}
+}
+
+/// Proprietary NVIDIA driver, with PRIME render offload. It only has one target, so the GPU is ignored
+#[derive(Debug)]
+pub struct Nvidia;
+
+impl GpuBackend for Nvidia {
+	fn name (&self) -> &'static str { "nvidia" }
+
+	fn offload (&self, _gpu :&str) -> Offload {
+		Offload {
+			unset: vec![DRI_PRIME.to_string()],
+			..Offload::vars(NVIDIA_VARS)
+		}
+	}
+	
+	fn integrated (&self, _gpu :&str) -> Offload {
+		Offload {
+			unset: vec!["__NV_PRIME_RENDER_OFFLOAD".to_string()],
+			..Offload::vars(NVIDIA_OFF_VARS)
+		}
+	}
+
+	fn index (&self, i :u32) -> Option<String> {
+		Some(i.to_string())
+	}
+}
+
+/// Bumblebee, which wraps the program with `optirun`. The GPU is ignored
+#[derive(Debug)]
+pub struct Bumblebee;
+
+impl GpuBackend for Bumblebee {
+	fn name (&self) -> &'static str { "bumblebee" }
+
+	fn offload (&self, _gpu :&str) -> Offload {
+		Offload {
+			wrapper: vec![OPTIRUN.to_string()],
+			..Offload::default()
+		}
+	}
+	
+	/// Without optirun, programs already run on the integrated GPU
+	fn integrated (&self, _gpu :&str) -> Offload {
+		Offload::default()
+	}
+
+	fn index (&self, i :u32) -> Option<String> {
+		Some(i.to_string())
+	}
+}
+
+/// Mesa's Vulkan device selection, the GPU is a `vendor:device` pair like `1002:687f`
+#[derive(Debug)]
+pub struct Vulkan;
+
+impl GpuBackend for Vulkan {
+	fn name (&self) -> &'static str { "vulkan" }
+
+	fn offload (&self, gpu :&str) -> Offload {
+		Offload::vars(&[(VK_DEVICE_SELECT, gpu)])
+	}
+
+	fn gpu_id (&self, gpu :&Gpu) -> String {
+		gpu.vendor_device()
+	}
+}
+
+/// The `backend` configuration key
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+	/// Detect the backend from the loaded kernel modules
+	Auto,
+	Mesa,
+	Nvidia,
+	Bumblebee,
+	Vulkan,
+}
+
+impl Backend {
+	/// Name as written in the config
+	pub fn name (self) -> &'static str {
+		match self {
+			Backend::Auto => "auto",
+			Backend::Mesa => "mesa",
+			Backend::Nvidia => "nvidia",
+			Backend::Bumblebee => "bumblebee",
+			Backend::Vulkan => "vulkan",
+		}
+	}
+
+	/// The implementation, where `Auto` is replaced with the detected backend
+	pub fn resolve (self) -> Box<dyn GpuBackend> {
+		match self {
+			Backend::Auto => {
+				if Path::new(NVIDIA_MODULE).exists() {
+					Box::new(Nvidia)
+				} else {
+					Box::new(Mesa)
+				}
+			},
+			Backend::Mesa => Box::new(Mesa),
+			Backend::Nvidia => Box::new(Nvidia),
+			Backend::Bumblebee => Box::new(Bumblebee),
+			Backend::Vulkan => Box::new(Vulkan),
+		}
+	}
+}
@@ -0,0 +1,570 @@
+/*! Splitting command lines into simple commands
+
+This implements the subset of the bash grammar needed to find which programs a command line
+runs: lists and pipelines (`;`, `&`, `|`, `&&`, `||`, newlines), subshells, command and
+process substitutions, quoting, comments, redirections and here-documents. The bodies of
+function definitions are skipped since they don't run yet, and so are case patterns.
+
+Expansions are not performed: `$HOME` stays as is, and a command substitution is kept as
+its source text in the word, while the commands inside it are returned as well.
+
+```rust
+let cmds = cmdline::split("FOO=1 steam 2>/dev/null | tee 'log file'");
+// [["FOO=1", "steam"], ["tee", "log file"]]
+```
+*/
+
+/// A simple command, eg. one side of a pipe
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SimpleCommand {
+	/// Words after quote removal, without redirections
+	pub words :Vec<String>,
+	/// Source text of the command, including redirections
+	pub text :String,
+}
+
+/// Words that start a compound command or a list inside it, and aren't programs
+const RESERVED_PREFIXES :&[&str] = &[
+	"!", "{", "}", "if", "then", "elif", "else", "fi", "do", "done", "while", "until", "esac",
+];
+/// Words that start a compound command header that runs nothing by itself
+const RESERVED_HEADERS :&[&str] = &["for", "case", "select", "in"];
+/// Reserved words that open a compound command, and those that close one
+const OPENERS :&[&str] = &["{", "if", "while", "until", "for", "select", "case"];
+const CLOSERS :&[&str] = &["}", "fi", "done", "esac"];
+
+/// Split the command line into its simple commands, in the order they appear
+/// (commands inside a substitution come before the command containing it)
+pub fn split (line :&str) -> Vec<SimpleCommand> {
+	let mut parser = Parser {
+		chars: line.chars().collect(),
+		pos: 0,
+		commands: Vec::new(),
+		heredocs: Vec::new(),
+		depth: 0,
+		body: None,
+		bodies: Vec::new(),
+		pattern: false,
+	};
+	parser.parse_list(false);
+	parser.commands
+}
+
+/// Whether the words end with `case word in`, after which come the patterns
+fn at_case_in (words :&[String]) -> bool {
+	let n = words.len();
+	n >= 3 && words[n - 1] == "in" && words[n - 3] == "case"
+		&& words[..n - 3].iter().all(|w| RESERVED_PREFIXES.contains(&w.as_str()))
+}
+
+/// Characters that end an unquoted word
+fn is_meta (c :char) -> bool {
+	matches!(c, ' ' | '\t' | '\n' | ';' | '&' | '|' | '(' | ')' | '<' | '>')
+}
+
+/// The state of the command being parsed
+struct Current {
+	words :Vec<String>,
+	/// Source character range of the command
+	start :Option<usize>,
+	end :usize,
+	/// Number of commands found before this one, the commands of its substitutions come after
+	first_command :usize,
+}
+
+impl Current {
+	fn new (first_command :usize) -> Current {
+		Current { words: Vec::new(), start: None, end: 0, first_command }
+	}
+
+	/// Extend the source range to include [from, to)
+	fn cover (&mut self, from :usize, to :usize) {
+		if self.start.is_none() {
+			self.start = Some(from);
+		}
+		self.end = to;
+	}
+}
+
+struct Parser {
+	chars :Vec<char>,
+	pos :usize,
+	commands :Vec<SimpleCommand>,
+	/// Delimiters of the here-documents whose body starts at the next newline,
+	/// and whether leading tabs are stripped (`<<-`)
+	heredocs :Vec<(String, bool)>,
+	/// Number of compound commands open
+	depth :usize,
+	/// Number of commands found when a function definition was read and its body is next
+	body :Option<usize>,
+	/// Depth and number of commands found where each function body being parsed starts.
+	/// Bodies aren't run when defined, so their commands are dropped when they close
+	bodies :Vec<(usize, usize)>,
+	/// Whether a case pattern is expected, eg. after `case x in` or `;;`
+	pattern :bool,
+}
+
+impl Parser {
+	fn peek (&self) -> Option<char> {
+		self.chars.get(self.pos).copied()
+	}
+
+	fn peek_at (&self, offset :usize) -> Option<char> {
+		self.chars.get(self.pos + offset).copied()
+	}
+
+	fn starts_with (&self, s :&str) -> bool {
+		s.chars().enumerate().all(|(i, c)| self.peek_at(i) == Some(c))
+	}
+
+	fn text (&self, from :usize, to :usize) -> String {
+		self.chars[from..to].iter().collect()
+	}
+
+	/// Parse commands until the end, or the closing parenthesis if `nested`
+	fn parse_list (&mut self, nested :bool) {
+		let mut cur = Current::new(self.commands.len());
+
+		while let Some(c) = self.peek() {
+			let start = self.pos;
+			match c {
+				')' if self.pattern && cur.words.first().is_none_or(|w| w != "esac") => {
+					// End of a case pattern, which isn't a command
+					self.pos += 1;
+					self.pattern = false;
+					cur = Current::new(self.commands.len());
+				},
+				'|' if self.pattern => self.pos += 1,
+				'(' if self.pattern && cur.words.is_empty() => self.pos += 1,
+				' ' | '\t' => self.pos += 1,
+				'\n' => {
+					self.pos += 1;
+					self.finish(&mut cur);
+					self.skip_heredocs();
+				},
+				'#' => {
+					while self.peek().is_some_and(|c| c != '\n') {
+						self.pos += 1;
+					}
+				},
+				')' if nested => {
+					self.pos += 1;
+					break;
+				},
+				';' if matches!(self.peek_at(1), Some(';') | Some('&')) => {
+					// End of a case clause: `;;`, `;&` or `;;&`
+					self.pos += if self.starts_with(";;&") { 3 } else { 2 };
+					self.finish(&mut cur);
+					self.pattern = true;
+				},
+				';' | '&' | '|' | ')' => {
+					self.pos += 1;
+					self.finish(&mut cur);
+				},
+				'(' if self.peek_at(1) == Some('(') && cur.words.is_empty() => {
+					// Arithmetic command
+					self.skip_balanced('(', ')');
+				},
+				'(' if cur.words.is_empty() => {
+					// Subshell
+					self.pos += 1;
+					self.parse_list(true);
+				},
+				'(' => {
+					// Function definition: `name () body`, the body isn't run now
+					self.skip_balanced('(', ')');
+					cur = Current::new(self.commands.len());
+					while self.peek().is_some_and(|c| c == ' ' || c == '\t' || c == '\n') {
+						self.pos += 1;
+					}
+					if self.peek() == Some('(') {
+						self.pos += 1;
+						self.parse_list(true);
+						self.commands.truncate(cur.first_command);
+					} else {
+						self.body = Some(cur.first_command);
+					}
+				},
+				'<' | '>' if self.peek_at(1) == Some('(') => {
+					// Process substitution, as an argument
+					let word = self.parse_word();
+					cur.words.push(word);
+					cur.cover(start, self.pos);
+				},
+				_ if self.at_redirection() => {
+					self.skip_redirection();
+					cur.cover(start, self.pos);
+				},
+				_ => {
+					let word = self.parse_word();
+					cur.words.push(word);
+					cur.cover(start, self.pos);
+					if at_case_in(&cur.words) {
+						self.finish(&mut cur);
+						self.pattern = true;
+					}
+				},
+			}
+		}
+
+		self.finish(&mut cur);
+	}
+
+	/// Save the current command if there is one, and start a new one
+	fn finish (&mut self, cur :&mut Current) {
+		let mut words = std::mem::take(&mut cur.words);
+		let start = cur.start.take();
+
+		// Drop the reserved words that aren't commands, following which compound commands
+		// are open
+		let mut skip = 0;
+		while let Some(word) = words.get(skip).map(String::as_str) {
+			if word == "function" {
+				// `function name body`
+				skip += 2;
+				self.body = Some(cur.first_command);
+				continue;
+			}
+			let reserved = RESERVED_PREFIXES.contains(&word) || RESERVED_HEADERS.contains(&word);
+			if let Some(first_command) = self.body.take() {
+				if !OPENERS.contains(&word) {
+					// A body that is a single command, like `[[ … ]]`
+					self.commands.truncate(first_command);
+					words.clear();
+					break;
+				}
+				self.bodies.push((self.depth, first_command));
+			}
+			if !reserved {
+				break;
+			}
+
+			if OPENERS.contains(&word) {
+				self.depth += 1;
+			} else if CLOSERS.contains(&word) {
+				self.depth = self.depth.saturating_sub(1);
+				if word == "esac" {
+					self.pattern = false;
+				}
+				while let Some(&(depth, first_command)) = self.bodies.last() {
+					if self.depth > depth {
+						break;
+					}
+					self.commands.truncate(first_command);
+					self.bodies.pop();
+				}
+			}
+			if RESERVED_HEADERS.contains(&word) {
+				skip = words.len();
+				break;
+			}
+			skip += 1;
+		}
+		words.drain(..skip.min(words.len()));
+
+		if let (Some(start), false) = (start, words.is_empty()) {
+			let text = self.text(start, cur.end).trim().to_string();
+			self.commands.push(SimpleCommand { words, text });
+		}
+		cur.first_command = self.commands.len();
+	}
+
+	/// Whether we are at a redirection operator, possibly with a file descriptor number
+	fn at_redirection (&self) -> bool {
+		let digits = self.chars[self.pos..].iter()
+			.take_while(|c| c.is_ascii_digit())
+			.count();
+		match self.peek_at(digits) {
+			Some('<') | Some('>') => true,
+			Some('&') => digits == 0 && self.peek_at(1) == Some('>'), // &> and &>>
+			_ => false,
+		}
+	}
+
+	/// Skip a redirection operator and its target word, remembering here-documents
+	fn skip_redirection (&mut self) {
+		while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+			self.pos += 1;
+		}
+
+		let heredoc = self.starts_with("<<") && !self.starts_with("<<<");
+		let strip_tabs = self.starts_with("<<-");
+		// Operator characters, eg. `>>`, `>&`, `<<-` or `>|`
+		while self.peek().is_some_and(|c| "<>&|-".contains(c)) {
+			self.pos += 1;
+		}
+		while self.peek().is_some_and(|c| c == ' ' || c == '\t') {
+			self.pos += 1;
+		}
+
+		if self.peek().is_some_and(|c| !is_meta(c)) {
+			let target = self.parse_word();
+			if heredoc {
+				self.heredocs.push((target, strip_tabs));
+			}
+		}
+	}
+
+	/// Skip the bodies of the pending here-documents, which start at the current line
+	fn skip_heredocs (&mut self) {
+		for (delim, strip_tabs) in std::mem::take(&mut self.heredocs) {
+			while self.pos < self.chars.len() {
+				let end = self.chars[self.pos..].iter()
+					.position(|&c| c == '\n')
+					.map_or(self.chars.len(), |i| self.pos + i);
+				let line = self.text(self.pos, end);
+				self.pos = (end + 1).min(self.chars.len());
+
+				let line = if strip_tabs { line.trim_start_matches('\t') } else { &line };
+				if line == delim {
+					break;
+				}
+			}
+		}
+	}
+
+	/// Skip from an opening character to its matching closing one, ignoring quotes
+	fn skip_balanced (&mut self, open :char, close :char) {
+		let mut depth = 0;
+		while let Some(c) = self.peek() {
+			self.pos += 1;
+			if c == open {
+				depth += 1;
+			} else if c == close {
+				depth -= 1;
+				if depth == 0 {
+					break;
+				}
+			}
+		}
+	}
+
+	/// Parse a word and return it with quotes removed
+	fn parse_word (&mut self) -> String {
+		let mut word = String::new();
+
+		while let Some(c) = self.peek() {
+			match c {
+				'\\' => {
+					self.pos += 1;
+					match self.peek() {
+						Some('\n') => self.pos += 1, // Line continuation
+						Some(c) => { word.push(c); self.pos += 1; },
+						None => {},
+					}
+				},
+				'\'' => {
+					self.pos += 1;
+					while let Some(c) = self.peek() {
+						self.pos += 1;
+						if c == '\'' {
+							break;
+						}
+						word.push(c);
+					}
+				},
+				'"' => {
+					self.pos += 1;
+					self.parse_double_quoted(&mut word);
+				},
+				'$' if self.peek_at(1) == Some('\'') => {
+					self.pos += 2;
+					self.parse_ansi_c(&mut word);
+				},
+				'$' if self.peek_at(1) == Some('"') => {
+					self.pos += 2;
+					self.parse_double_quoted(&mut word);
+				},
+				'$' | '`' => self.parse_expansion(&mut word),
+				'<' | '>' if word.is_empty() && self.peek_at(1) == Some('(') => {
+					// Process substitution
+					let start = self.pos;
+					self.pos += 2;
+					self.parse_list(true);
+					word.push_str(&self.text(start, self.pos));
+				},
+				c if is_meta(c) => break,
+				c => {
+					word.push(c);
+					self.pos += 1;
+				},
+			}
+		}
+
+		word
+	}
+
+	/// Parse the inside of double quotes, after the opening quote
+	fn parse_double_quoted (&mut self, word :&mut String) {
+		while let Some(c) = self.peek() {
+			match c {
+				'"' => {
+					self.pos += 1;
+					break;
+				},
+				'\\' => {
+					self.pos += 1;
+					match self.peek() {
+						Some('\n') => self.pos += 1,
+						Some(c) if "$`\"\\".contains(c) => { word.push(c); self.pos += 1; },
+						_ => word.push('\\'),
+					}
+				},
+				'$' | '`' => self.parse_expansion(word),
+				c => {
+					word.push(c);
+					self.pos += 1;
+				},
+			}
+		}
+	}
+
+	/// Parse the inside of `$'…'` quotes, after the opening quote
+	fn parse_ansi_c (&mut self, word :&mut String) {
+		while let Some(c) = self.peek() {
+			self.pos += 1;
+			match c {
+				'\'' => break,
+				'\\' => {
+					let escaped = match self.peek() {
+						Some('n') => '\n',
+						Some('t') => '\t',
+						Some('e') | Some('E') => '\x1b',
+						Some(c) => c,
+						None => break,
+					};
+					word.push(escaped);
+					self.pos += 1;
+				},
+				c => word.push(c),
+			}
+		}
+	}
+
+	/// Parse `$…` or a backquoted command substitution, keeping its source text in the word.
+	/// The commands of substitutions are parsed too
+	fn parse_expansion (&mut self, word :&mut String) {
+		let start = self.pos;
+
+		if self.starts_with("$((") {
+			// Arithmetic expansion
+			self.pos += 1;
+			self.skip_balanced('(', ')');
+		} else if self.starts_with("$(") {
+			self.pos += 2;
+			self.parse_list(true);
+		} else if self.starts_with("${") {
+			self.pos += 1;
+			self.skip_balanced('{', '}');
+		} else if self.peek() == Some('`') {
+			self.pos += 1;
+			let mut inner = String::new();
+			while let Some(c) = self.peek() {
+				self.pos += 1;
+				match c {
+					'`' => break,
+					'\\' => if let Some(c) = self.peek() {
+						inner.push(c);
+						self.pos += 1;
+					},
+					c => inner.push(c),
+				}
+			}
+			self.commands.extend(split(&inner));
+		} else {
+			// A simple `$name`, or a lone `$`
+			self.pos += 1;
+		}
+
+		word.push_str(&self.text(start, self.pos));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// The words of each simple command of the line
+	fn words (line :&str) -> Vec<Vec<String>> {
+		split(line).into_iter().map(|c| c.words).collect()
+	}
+
+	/// The first word of each simple command of the line
+	fn programs (line :&str) -> Vec<String> {
+		split(line).into_iter().filter_map(|c| c.words.into_iter().next()).collect()
+	}
+
+	#[test]
+	fn pipes () {
+		assert_eq!(words("steam | tee log |& cat"), [vec!["steam"], vec!["tee", "log"], vec!["cat"]]);
+	}
+
+	#[test]
+	fn lists () {
+		assert_eq!(programs("a && b || c; d & e\nf"), ["a", "b", "c", "d", "e", "f"]);
+		assert_eq!(programs("(a; b) && { c; }"), ["a", "b", "c"]);
+		assert_eq!(programs("if a; then b; else c; fi"), ["a", "b", "c"]);
+		assert_eq!(programs("while a; do b; done"), ["a", "b"]);
+	}
+
+	#[test]
+	fn quoting () {
+		assert_eq!(words(r#"echo 'a b' "c $d" e\ f 'g'"h""#), [vec!["echo", "a b", "c $d", "e f", "gh"]]);
+		assert_eq!(words(r#"echo "a \"b\" \$c" 'd\e'"#), [vec!["echo", r#"a "b" $c"#, r"d\e"]]);
+		assert_eq!(words("echo 'a;b' \"c|d\" e\\&\\&f"), [vec!["echo", "a;b", "c|d", "e&&f"]]);
+	}
+
+	#[test]
+	fn comments () {
+		assert_eq!(words("steam # && rm -rf ~"), [vec!["steam"]]);
+		assert_eq!(words("echo a#b"), [vec!["echo", "a#b"]]);
+	}
+
+	#[test]
+	fn redirections () {
+		assert_eq!(words("steam >log 2>&1 < in"), [vec!["steam"]]);
+		assert_eq!(words("2>/dev/null steam -silent &>> log"), [vec!["steam", "-silent"]]);
+		assert_eq!(split("steam >log")[0].text, "steam >log");
+	}
+
+	#[test]
+	fn substitutions () {
+		assert_eq!(programs("echo $(steam) `lutris`"), ["steam", "lutris", "echo"]);
+		assert_eq!(programs("echo \"$(a \"$(b)\")\""), ["b", "a", "echo"]);
+		assert_eq!(programs("cat <(steam) >(tee log)"), ["steam", "tee", "cat"]);
+		assert_eq!(words("echo $(steam)"), [vec!["steam"], vec!["echo", "$(steam)"]]);
+	}
+
+	#[test]
+	fn heredocs () {
+		assert_eq!(programs("cat <<EOF\nsteam\nEOF\nlutris"), ["cat", "lutris"]);
+		assert_eq!(programs("cat <<-'EOF' | a\n\tsteam\n\tEOF\nb"), ["cat", "a", "b"]);
+	}
+
+	#[test]
+	fn functions () {
+		assert_eq!(programs("f() { steam; }; lutris"), ["lutris"]);
+		assert_eq!(programs("f () {\n\tsteam | cat\n}\nf"), ["f"]);
+		assert_eq!(programs("f() { if a; then steam; fi; }; b"), ["b"]);
+		assert_eq!(programs("f() ( steam ); function g { $(steam); }; function h () [[ -x a ]]; b"), ["b"]);
+	}
+
+	#[test]
+	fn cases () {
+		assert_eq!(programs("case $x in a|b) steam;; (c) lutris ;& *) ;; esac; d"), ["steam", "lutris", "d"]);
+		assert_eq!(programs("echo $(case x in a) steam;; esac)"), ["steam", "echo"]);
+		assert_eq!(programs("case x in\na)\n\tsteam\nesac"), ["steam"]);
+	}
+
+	#[test]
+	fn assignments () {
+		assert_eq!(words("FOO=1 BAR='a b' steam"), [vec!["FOO=1", "BAR=a b", "steam"]]);
+		assert_eq!(words("FOO=1"), [vec!["FOO=1"]]);
+	}
+
+	#[test]
+	fn arguments_are_not_programs () {
+		assert_eq!(programs("echo steam"), ["echo"]);
+		assert_eq!(programs("echo steam && sudo steam"), ["echo", "sudo"]);
+	}
+}
@@ -5,15 +5,36 @@ Glossary:
 */
 
 prelude!();
-use std::error::Error as _;
+use std::error::Error;
 use crate::exitcode::{self, ExitCode};
-use crate::{config, file, alias, app, util};
+use crate::{config, file, alias, app, util, daemon, shim};
+use crate::shell::{self, Shell};
+use crate::backend::Backend;
+use crate::matcher::Matcher;
+use crate::session::{Session, Change};
+use crate::util::Lookup;
 use regex::Regex;
+use std::collections::BTreeMap;
 
-/// The env variable to set
-const DRI_PRIME :&str = "DRI_PRIME";
 /// Name used in init and preexec hooks
-const INIT_NAME :&str = "switchable";
+pub const INIT_NAME :&str = "switchable";
+/// Records which hook implementation the shell ended up using
+const HOOK_VAR :&str = "SWITCHABLE_HOOK";
+/// Records the variables changed by preexec, for precmd
+const VARS_VAR :&str = "SWITCHABLE_VARS";
+
+/// Prints the error and its source to stderr
+fn print_error (e :&dyn Error) {
+	eprintln!("{}", e);
+	print_source(e);
+}
+
+/// Prints the source of the error to stderr, if it has one
+fn print_source (e :&dyn Error) {
+	if let Some(ee) = e.source() {
+		eprintln!("{}", ee);
+	}
+}
 
 /// Entry point, dispatches to the right subcommand
 pub fn execute (p_name :&str, args :Vec<String>) -> ExitCode {
@@ -21,11 +42,17 @@ pub fn execute (p_name :&str, args :Vec<String>) -> ExitCode {
 	
 	match args[0].as_str() {
 		"_test" => test_func(),
+		"_shim" => shim_exec(n_args),
 		"run" => run_subcommand(p_name, n_args),
-		"init" => init_subcommand(),
+		"init" => init_subcommand(p_name, n_args),
 		"preexec" => preexec_subcommand(n_args),
 		"precmd" => precmd_subcommand(),
-		"xrandr" => xrandr_subcommand(p_name, n_args),
+		"which" => which_subcommand(p_name, n_args),
+		"daemon" => daemon_subcommand(p_name, n_args),
+		"off" | "on" | "set-driver" => session_subcommand(p_name, &args[0], n_args),
+		"status" => status_subcommand(p_name, n_args),
+		"shims" => shims_subcommand(p_name, n_args),
+		"gpus" => gpus_subcommand(p_name, n_args),
 		"show-config" => show_config_subcommand(),
 		"reload-aliases" => reload_aliases_subcommand(),
 		v => {
@@ -48,6 +75,10 @@ fn run_subcommand (p_name :&str, args :&[String]) -> ExitCode {
 	use std::process::Command;
 	use std::os::unix::process::CommandExt;
 	use std::env;
+	use std::path::PathBuf;
+	use config::Driver;
+	use crate::gpu;
+	use crate::rule::Precedence;
 
 	fn print_help (p_name :&str) {
 		print!(
@@ -56,7 +87,8 @@ r#"Usage:
 
 Options:
   --help, -h             Display this help text
-  --driver, -d <string>  The value of DRI_PRIME
+  --driver, -d <string>  The GPU to use, ie. the value of DRI_PRIME
+  --integrated, -i       Use the integrated GPU, even if the discrete one is the default
   --expand               Pass the command as a string to eval
 "#,
 		p_name = p_name);
@@ -69,12 +101,13 @@ Options:
 		parser.optflag("h", "help", "");
 		parser.optflag("", "expand", "");
 		parser.optopt("d", "driver", "", "");
+		parser.optflag("i", "integrated", "");
 		parser
 	}
 	
-	fn get_config_driver () -> Option<String> {
+	fn get_config () -> Option<config::FullConfig> {
 		match config::load_config() {
-			Ok(config) => Some(config.driver),
+			Ok(config) => Some(config),
 			Err(e) => {
 				eprintln!("{}", e);
 				None
@@ -83,7 +116,7 @@ Options:
 	}
 	
 	fn parser_f (e :getopts::Fail) -> ExitCode {
-		eprint!("{}", e.to_string());
+		eprint!("{}", e);
 		exitcode::BAD_ARG
 	}
 	
@@ -99,82 +132,253 @@ Options:
 		exitcode::OK
 	}
 	
+	tear_if! { opts.opt_present("driver") && opts.opt_present("integrated"),
+		eprintln!("--driver and --integrated can't be used together");
+		exitcode::BAD_ARG
+	}
+	
 	// Modify env
-	let driver = opts.opt_str("driver").or_else(get_config_driver);
-	let driver :&str = driver.as_deref().unwrap_or("1");
-	env::set_var(DRI_PRIME, driver);
+	let config = get_config();
+	let driver = opts.opt_str("driver").map(|v| Driver::parse(&v))
+		.or_else(|| opts.opt_present("integrated").then_some(Driver::Integrated))
+		.or_else(|| config.as_ref().map(|v| v.driver.clone()))
+		.unwrap_or_default();
+	// The options are an explicit choice, so only the configured driver gives way
+	let from_options = opts.opt_present("driver") || opts.opt_present("integrated");
+	let (backend, sysfs, precedence) = match config {
+		Some(v) => (v.backend, v.sysfs, v.precedence),
+		None => (Backend::Auto.resolve(), PathBuf::from(gpu::SYSFS_ROOT), Precedence::default()),
+	};
+	let mut offload = tear! { driver.offload(&*backend, &sysfs) => |e| {
+		eprintln!("{}", e);
+		exitcode::FAIL
+	}};
+	if precedence == Precedence::User && !from_options {
+		offload.keep_env(&util::environment());
+	}
+	for var in &offload.unset {
+		env::remove_var(var);
+	}
+	for (var, value) in &offload.set {
+		env::set_var(var, value);
+	}
 	
-	// Execute the command
+	// Execute the command, through the backend's wrapper if any
 	// See docs/bash_splitting for details on how the arguments are handled
-	let mut command;
+	let mut argv :Vec<String> = offload.wrapper;
 	if opts.opt_present("expand") {
 		// Pass the string to sh to perform shell expansion
-		command = Command::new("sh");
-		command.arg("-c").arg(args.join(" "));
+		argv.extend(vec!["sh".to_string(), "-c".to_string(), args.join(" ")]);
 	} else {
 		// Otherwise, keep the arguments as is
-		command = Command::new(&args[0]);
-		command.args(args[1..].iter());
+		argv.extend(args.iter().cloned());
 	};
-	command.exec();
+	let e = Command::new(&argv[0]).args(argv[1..].iter()).exec();
 	
-	exitcode::OK // This will never be reached because we exec
+	// exec only returns on failure
+	eprintln!("Failed to execute '{}': {}", argv[0], e);
+	exitcode::FAIL
 }
 
-/// Prints out shell code to load aliases
-fn init_subcommand () -> ExitCode {
-	use config::FullConfig;
+/// Prints out shell code to load the hooks and aliases
+fn init_subcommand (p_name :&str, args :&[String]) -> ExitCode {
+	use config::{FullConfig, Hook, PreexecFrom};
 	use std::path::PathBuf;
 	
-	fn setup_preexec (path :&Option<PathBuf>) {
-		fn path_not_utf8 () {
-			eprintln!("bash_preexec path is not utf8")
-		}
-		
-		// Get path
-		tear_if! { path.is_none(), }
-		let path = path.as_ref().unwrap();
-		tear_if! { !path.exists(),
-			eprintln!("Could not find bash_preexec")
-		}
-		
-		// Load preexec
-		let path = tear! { path.to_str() => |_| path_not_utf8() };
-		println!("source \"{}\"", path);
+	fn print_help (p_name :&str) {
+		let shells :Vec<&str> = Shell::ALL.iter().map(|v| v.name()).collect();
+		print!(
+r#"Usage:
+  {p_name} init [shell]
+
+Prints the shell code to evaluate in your shell's rc file.
+Supported shells: {shells} (default: bash)
+"#,
+		p_name = p_name, shells = shells.join(", "));
+	}
+	
+	/// Use bash-preexec, or ble.sh's compatible hooks. What is already loaded in the shell
+	/// is only known at runtime, so the choice is made by the shell code, and remembered
+	/// in `SWITCHABLE_HOOK`.
+	fn setup_bash_preexec (path :&Option<Metadata<PreexecFrom, PathBuf>>) {
+		// Source the file, or complain if there is none
+		let load = match path.as_ref().map(|Metadata(_, p)| (p, p.to_str())) {
+			Some((p, Some(s))) if p.exists() => format!(
+				"source {path}\n\t\t{hook}",
+				path = util::shell_escape(s),
+				hook = Shell::Bash.export(HOOK_VAR, &format!("bash-preexec ({})", s))),
+			Some((_, None)) =>
+				Shell::Bash.echo_err("switchable: bash-preexec path is not utf8"),
+			_ => Shell::Bash.echo_err(
+				r#"switchable: Could not find bash-preexec, set `hook = "builtin"` to use our own hooks"#),
+		};
 		
 		// Define hooks. '{{' and '}}' are for escaping
-		print!(r#"
+		print!(r#"sw_preexec() {{ eval "$( {pn} preexec "$1" )"; }}
+sw_precmd() {{ eval "$( {pn} precmd )"; }}
+if [ -n "${{BLE_VERSION-}}" ]
+then
+	blehook PREEXEC+=sw_preexec
+	blehook PRECMD+=sw_precmd
+	{ble}
+else
+	if declare -F __bp_preexec_invoke_exec >/dev/null
+	then
+		{loaded}
+	else
+		{load}
+	fi
+	preexec_functions+=(sw_preexec)
+	precmd_functions+=(sw_precmd)
+fi
+"#,
+			pn = INIT_NAME,
+			ble = Shell::Bash.export(HOOK_VAR, "ble.sh"),
+			loaded = Shell::Bash.export(HOOK_VAR, "bash-preexec (already loaded)"),
+			load = load,
+		);
+	}
+	
+	/// Our own minimal bash-preexec: a DEBUG trap guarded by a flag.
+	/// The flag is raised by PS0 once a command line has been read (bash ≥ 5),
+	/// or by the last PROMPT_COMMAND otherwise. It replaces any existing DEBUG trap.
+	/// The prompt commands keep `$?`, so that the ones after ours still see the status.
+	/// The command line is read from history, or from `$BASH_COMMAND` when it wasn't saved.
+	fn setup_bash_builtin () {
+		print!(r#"__sw_at_prompt=
+__sw_preexec_trap() {{
+	[ -n "$__sw_at_prompt" ] || return
+	[ -n "$COMP_LINE" ] && return
+	case "$BASH_COMMAND" in __sw_*) return ;; esac
+	__sw_at_prompt=
+	local cmd
+	cmd="$(HISTTIMEFORMAT= builtin history 1)"
+	# Not saved to history, eg. ignored as a duplicate
+	[ "$cmd" = "$__sw_last_hist" ] && cmd=
+	cmd="${{cmd#*[0-9][* ] }}"
+	eval "$( {pn} preexec "${{cmd:-$BASH_COMMAND}}" )"
+}}
+__sw_precmd() {{
+	local ret=$?
+	__sw_at_prompt=
+	eval "$( {pn} precmd )"
+	__sw_last_hist="$(HISTTIMEFORMAT= builtin history 1)"
+	return $ret
+}}
+__sw_ready() {{ local ret=$?; __sw_at_prompt=1; return $ret; }}
+trap '__sw_preexec_trap' DEBUG
+{hook}
+# Commands starting with a space must be saved to history to be seen, like with bash-preexec
+HISTCONTROL="${{HISTCONTROL//ignorespace}}"
+HISTCONTROL="${{HISTCONTROL//ignoreboth/ignoredups}}"
+PROMPT_COMMAND="__sw_precmd${{PROMPT_COMMAND:+;$PROMPT_COMMAND}}"
+if (( BASH_VERSINFO[0] >= 5 ))
+then
+	PS0="${{PS0}}"'${{__sw_ps0[__sw_at_prompt=1]}}'
+else
+	PROMPT_COMMAND="$PROMPT_COMMAND;__sw_ready"
+fi
+"#, pn=INIT_NAME, hook=Shell::Bash.export(HOOK_VAR, "builtin")
+		);
+	}
+	
+	/// zsh has native hooks, so we don't need bash-preexec
+	fn setup_zsh_hooks () {
+		print!(r#"autoload -Uz add-zsh-hook
 sw_preexec() {{ eval "$( {pn} preexec "$1" )"; }}
-sw_precmd() {{ eval "$( {pn} precmd "$1" )"; }}
-preexec_functions+=(sw_preexec)
-precmd_functions+=(sw_precmd)
+sw_precmd() {{ eval "$( {pn} precmd )"; }}
+add-zsh-hook preexec sw_preexec
+add-zsh-hook precmd sw_precmd
+"#, pn=INIT_NAME
+		);
+	}
+	
+	/// fish emits events before and after each command
+	fn setup_fish_hooks () {
+		print!(r#"function sw_preexec --on-event fish_preexec
+	{pn} preexec $argv[1] | source
+end
+function sw_postexec --on-event fish_postexec
+	{pn} precmd | source
+end
 "#, pn=INIT_NAME
 		);
 	}
 	
-	fn setup_aliases (config :&FullConfig){
-		match alias::write_aliases(config) {
-			Ok(file) => {
-				let file = file.replace("'", r#"'\''"#);
-				println!("source '{}'", file);
+	fn setup_aliases (config :&FullConfig, sh :Shell) {
+		match alias::write_aliases(config, sh) {
+			Ok(written) => {
+				for e in written.skipped {
+					eprintln!("{}, it is left out", e);
+					print_source(&e);
+				}
+				println!("{}", sh.source(&written.path));
 			},
 			Err(e) => {
-				eprintln!("{}", e);
+				print_error(&e);
 			}
 		}
 	}
 	
+	/// Put the shims first in PATH, if `switchable shims` wrote them
+	fn setup_shims (sh :Shell) {
+		let dir = tear! { file::shims_dir().filter(|d| d.is_dir()) };
+		let dir = sh.escape(&dir.to_string_lossy());
+		if sh.is_posix() {
+			print!(r#"case ":$PATH:" in
+	*:{dir}:*) ;;
+	*) export PATH={dir}:"$PATH" ;;
+esac
+"#, dir=dir);
+		} else {
+			println!("contains -- {dir} $PATH; or set -gx PATH {dir} $PATH", dir=dir);
+		}
+	}
+	
+	fn parser_f (e :getopts::Fail) -> ExitCode {
+		eprint!("{}", e);
+		exitcode::BAD_ARG
+	}
+	
+	// Parse arguments
+	let mut parser = getopts::Options::new();
+	parser.optflag("h", "help", "");
+	let opts = tear! { parser.parse(args) => parser_f };
+	
+	tear_if! { opts.opt_present("help"),
+		print_help(p_name);
+		exitcode::OK
+	}
+	
+	let sh :Shell = match opts.free.first() {
+		Some(v) => tear! { v.parse() => |e :String| {
+			eprintln!("{}, see `{} init --help`", e, p_name);
+			exitcode::BAD_ARG
+		}},
+		None => Shell::Bash,
+	};
+	
 	// Load config or die
 	let config = tear! { 
 		config::load_config() => |e| { eprintln!("{}", e); exitcode::FAIL }
 	};
 	
-	// Load bash-preexec and write aliases
-	setup_preexec(&config.preexec);
+	// Load the hooks and write aliases
+	match sh {
+		Shell::Bash => match config.hook {
+			Hook::BashPreexec => setup_bash_preexec(&config.preexec_path()),
+			Hook::Builtin => setup_bash_builtin(),
+		},
+		Shell::Zsh => setup_zsh_hooks(),
+		Shell::Fish => setup_fish_hooks(),
+	}
 	println!();
-	setup_aliases(&config);
+	setup_aliases(&config, sh);
+	setup_shims(sh);
 	
-	println!("\nexport SWITCHABLE_EXISTS=1");
+	println!();
+	println!("{}", sh.export(shell::SHELL_VAR, sh.name()));
+	println!("{}", sh.export("SWITCHABLE_EXISTS", "1"));
 	exitcode::OK
 }
 
@@ -185,10 +389,9 @@ Make sure eveything is quoted properly !
 */
 fn preexec_subcommand (args :&[String]) -> ExitCode {
 	#![allow(clippy::print_literal)]
-	use util::shell_escape;
 	use config::FullConfig;
 	
-	fn load_config_f (x :config::Result<FullConfig>)
+	fn load_config_f (x :config::Result<FullConfig>, sh :Shell)
 		-> ValRet<Option<FullConfig>, ExitCode>
 	{
 		use config::Error::*;
@@ -196,82 +399,469 @@ fn preexec_subcommand (args :&[String]) -> ExitCode {
 			Ok(v) => Val(Some(v)),
 			Err(NoFileF(..)) => Val(None),
 			Err(_) => {
-				println!(r#"echo 'Failed to load {} config' >&2"#, INIT_NAME);
+				println!("{}", sh.echo_err(&format!("Failed to load {} config", INIT_NAME)));
 				Ret(exitcode::FAIL)
 			}
 		}
 	}
 	
 	// No warnings as this executed at every command entered in the shell
-	let command = tear! { args.get(0) => |_| exitcode::MISSING_ARG };
+	let command = tear! { args.first() => |_| exitcode::MISSING_ARG };
+	let sh = Shell::from_env();
 	
 	// Process `switchable reload-aliases`
 	let reload_re = Regex::new(r"(?x)
 		^ \s* (?: (?: \w | [/.] )* )? switchable      # switchable
 		\s+ reload-aliases      # followed by the `reload-aliases` subcommand")
 		.unwrap();
-	if reload_re.is_match(&command) {
-		let sayf = |v :String| format!("echo {}", shell_escape(&v));
+	if reload_re.is_match(command) {
+		let sayf = |v :String| sh.echo(&v);
 		
 		// We don't handle Err as it is already done by reload_aliases
-		if let Ok(aliases_file) = app::reload_aliases(sayf) {
-			println!("source {}", shell_escape(&aliases_file));
-			println!(r#"echo 'Loaded new aliases in this shell'"#);
+		if let Ok(aliases_file) = app::reload_aliases(sayf, sh) {
+			println!("{}", sh.source(&aliases_file));
+			println!("{}", sh.echo("Loaded new aliases in this shell"));
 		}
 	}
 	
-	// Process configured matches
-	let config = tear! { load_config_f( config::load_config() ) };
-	if let Some(conf) = config {
-		// Set DRI_PRIME if needed
-		let Metadata(some_failed, matched) = app::matches_command(&conf, command);
-		if matched {
-			print!("{}",
-r#"if [ -n "${DRI_PRIME+x}" ]
-then
-	export SWITCHABLE_DP_BAK="$DRI_PRIME"
-fi
-"#
-			);
-			println!("export DRI_PRIME={}", util::shell_escape(&conf.driver));
+	// Process `switchable off`, `on` and `set-driver`, before the commands after them.
+	// Invalid drivers are left to `set-driver` to report
+	let mut env = util::environment();
+	let changes = Change::from_line(command);
+	let conf = if changes.is_empty() { None } else { config::load_config().ok() };
+	for change in changes {
+		if change.check(conf.as_ref()).is_err() {
+			continue;
 		}
-		
-		// If regex are invalid, warn but keep it short
-		if some_failed {
-			println!(r#"echo '{pn}: Invalid regex found, see `{pn} show-config`' >&2"#, pn=INIT_NAME);
+		print!("{}", change.code(sh));
+		change.apply(&mut env);
+	}
+	
+	// Ask the daemon first, as it has the configuration loaded
+	let request = daemon::Request {
+		shell: sh.name().to_string(),
+		line: command.clone(),
+		env,
+		cwd: std::env::current_dir().ok(),
+	};
+	if let Some(output) = daemon::request(&request) {
+		print!("{}", output);
+	} else {
+		// Process configured matches
+		let config = tear! { load_config_f(config::load_config(), sh) };
+		if let Some(conf) = config {
+			let matcher = Matcher::load(&conf);
+			print!("{}", preexec_code(&conf, &matcher, sh, &request));
+			matcher.save();
 		}
 	}
 	
-	println!("export SWITCHABLE_RAN=1");
+	println!("{}", sh.export("SWITCHABLE_RAN", "1"));
 	exitcode::OK
 }
 
+/// Shell code changing the variables for the command line of the request, in the environment
+/// and directory of the shell that sent it, used by preexec and the daemon
+fn preexec_code (conf :&config::FullConfig, matcher :&Matcher, sh :Shell, req :&daemon::Request) -> String {
+	let (command, env) = (&req.line, &req.env);
+	let mut out = String::new();
+	/// Our writer, which adds a newline
+	macro_rules! w {
+		($e:expr) => {
+			out.push_str(&$e);
+			out.push('\n');
+		}
+	}
+	
+	// Change the rule's variables if needed, unless offloading is off in the shell.
+	// We can't use wrappers here, so we say so
+	let lookup = Lookup::of_shell(env, req.cwd.clone());
+	let matches = app::matches_command(conf, matcher, &lookup, command);
+	let offload = match Session::from_env(env).off {
+		false => matches.offload(conf, env),
+		true => None,
+	};
+	if let (Some(m), Some(offload)) = (matches.winner(), offload) {
+		match offload {
+			Ok(offload) => {
+				for var in &offload.unset {
+					out.push_str(&save_var(sh, var));
+					w!(sh.unset(var));
+				}
+				for (var, value) in &offload.set {
+					out.push_str(&save_var(sh, var));
+					w!(sh.export(var, value));
+				}
+				
+				// Tell precmd which variables to restore, adding to the ones of a previous
+				// preexec if precmd didn't run in between
+				let vars :Vec<&str> = offload.unset.iter()
+					.chain(offload.set.iter().map(|(k, _)| k))
+					.map(String::as_str)
+					.collect();
+				if vars.is_empty() {
+					// Everything is left to the environment
+				} else if sh.is_posix() {
+					w!(format!(r#"export {v}="${{{v}-}} {}""#, vars.join(" "), v=VARS_VAR));
+				} else {
+					w!(format!("set -gx {v} ${v} {}", vars.join(" "), v=VARS_VAR));
+				}
+
+				if !offload.wrapper.is_empty() {
+					let msg = format!("{pn}: The hook can't run `{cmd}` through `{w}`, use `{pn} run` or an alias",
+						pn=INIT_NAME, cmd=m.command.text, w=offload.wrapper.join(" "));
+					w!(sh.echo_err(&msg));
+				}
+			},
+			Err(e) => {
+				let msg = format!("{}: {} (matched `{}`)", INIT_NAME, e, m.command.text);
+				w!(sh.echo_err(&msg));
+			},
+		}
+	}
+	
+	// If patterns are invalid, warn but keep it short
+	if let Some(p) = matcher.invalid.first() {
+		let msg = format!("{pn}: Invalid pattern {loc}, see `{pn} show-config`", pn=INIT_NAME, loc=p.location());
+		w!(sh.echo_err(&msg));
+	}
+	
+	out
+}
+
+/// Runs the daemon answering preexec
+fn daemon_subcommand (p_name :&str, args :&[String]) -> ExitCode {
+	use std::str::FromStr;
+	
+	fn print_help (p_name :&str) {
+		print!(
+r#"Usage:
+  {p_name} daemon [options]
+
+Keeps the configuration loaded and answers the preexec hook through a socket
+in $XDG_RUNTIME_DIR, so that it doesn't load it for every command.
+When the daemon isn't running, the hook works as usual.
+
+Options:
+  --help, -h  Display this help text
+"#,
+		p_name = p_name);
+	}
+	
+	fn parser_f (e :getopts::Fail) -> ExitCode {
+		eprint!("{}", e);
+		exitcode::BAD_ARG
+	}
+	
+	let mut parser = getopts::Options::new();
+	parser.optflag("h", "help", "");
+	let opts = tear! { parser.parse(args) => parser_f };
+	tear_if! { opts.opt_present("help"),
+		print_help(p_name);
+		exitcode::OK
+	}
+	
+	let answer = |conf :&config::FullConfig, matcher :&Matcher, req :&daemon::Request| {
+		let sh = Shell::from_str(&req.shell).unwrap_or(Shell::Bash);
+		preexec_code(conf, matcher, sh, req)
+	};
+	match daemon::serve(answer) {
+		Ok(()) => exitcode::OK,
+		Err(e) => {
+			print_error(&e);
+			exitcode::FAIL
+		},
+	}
+}
+
+/** Shell code recording the state of the variable before preexec changes it
+
+The state is kept in shell variables which aren't exported, `SWITCHABLE_WAS_<var>` being
+`exported`, `set` or `unset`, and `SWITCHABLE_BAK_<var>` the value. A variable already recorded
+is left alone, so that its state is the one from before the first change.
+*/
+fn save_var (sh :Shell, var :&str) -> String {
+	match sh {
+		Shell::Bash | Shell::Zsh => {
+			let exported = match sh {
+				Shell::Zsh => format!("[[ ${{(t){var}}} == *export* ]]", var=var),
+				// Not ${var@a}, which needs bash 4.4
+				_ => format!(r"[[ $(declare -p {var} 2>/dev/null) =~ ^declare\ -[a-zA-Z]*x ]]", var=var),
+			};
+			format!(
+r#"if [ -z "${{SWITCHABLE_WAS_{var}+x}}" ]
+then
+	if {exported}
+	then
+		SWITCHABLE_WAS_{var}=exported
+	elif [ -n "${{{var}+x}}" ]
+	then
+		SWITCHABLE_WAS_{var}=set
+	else
+		SWITCHABLE_WAS_{var}=unset
+	fi
+	SWITCHABLE_BAK_{var}="${{{var}-}}"
+fi
+"#, var=var, exported=exported)
+		},
+		Shell::Fish => format!(
+r#"if not set -q SWITCHABLE_WAS_{var}
+	if contains -- {var} (set -nx)
+		set -g SWITCHABLE_WAS_{var} exported
+	else if set -q {var}
+		set -g SWITCHABLE_WAS_{var} set
+	else
+		set -g SWITCHABLE_WAS_{var} unset
+	end
+	set -g SWITCHABLE_BAK_{var} ${var}
+end
+"#, var=var),
+	}
+}
+
+/// Shell code putting the variable back in the state recorded by `save_var`.
+/// Nothing is changed if there is no recorded state
+fn restore_var (sh :Shell, var :&str) -> String {
+	if sh.is_posix() {
+		format!(
+r#"case "${{SWITCHABLE_WAS_{var}-}}" in
+	exported) export {var}="$SWITCHABLE_BAK_{var}" ;;
+	set) unset {var}; {var}="$SWITCHABLE_BAK_{var}" ;;
+	unset) unset {var} ;;
+esac
+unset SWITCHABLE_WAS_{var} SWITCHABLE_BAK_{var}
+"#, var=var)
+	} else {
+		format!(
+r#"switch "$SWITCHABLE_WAS_{var}"
+	case exported
+		set -gx {var} $SWITCHABLE_BAK_{var}
+	case set
+		set -e {var}
+		set -g {var} $SWITCHABLE_BAK_{var}
+	case unset
+		set -e {var}
+end
+set -e SWITCHABLE_WAS_{var}
+set -e SWITCHABLE_BAK_{var}
+"#, var=var)
+	}
+}
+
 /** Cleanup things from preexec
 
 the output of this command is executed in the shell
 precmd is executed even if there was nothing entered in the shell
 */
-#[allow(clippy::print_literal)]
 fn precmd_subcommand () -> ExitCode {
-	print!("{}",
-r#"unset SWITCHABLE_RAN
-unset DRI_PRIME
+	let sh = Shell::from_env();
+	
+	println!("{}", sh.unset("SWITCHABLE_RAN"));
+	
+	// Only the variables preexec recorded are restored, the others are the user's
+	let recorded = std::env::var(VARS_VAR).unwrap_or_default();
+	let mut vars :Vec<&str> = Vec::new();
+	for var in recorded.split_whitespace() {
+		if !vars.contains(&var) {
+			vars.push(var);
+		}
+	}
+	for var in vars {
+		print!("{}", restore_var(sh, var));
+	}
+	println!("{}", sh.unset(VARS_VAR));
+	exitcode::OK
+}
 
-if [ -n ${SWITCHABLE_DP_BAK+x} ]
-then
-	DRI_PRIME="$SWITCHABLE_DP_BAK"
-	unset SWITCHABLE_DP_BAK
-fi
-"#
-	);
+
+/// Explains what preexec and run would do for a command line
+fn which_subcommand (p_name :&str, args :&[String]) -> ExitCode {
+	use crate::backend::Offload;
+	use crate::rule::Precedence;
+	use app::Outcome;
+	
+	fn print_help (p_name :&str) {
+		print!(
+r#"Usage:
+  {p_name} which [options] <command>
+
+Explains which rules match the command line, and what the preexec hook and
+`{p_name} run` would change to run it.
+
+Options:
+  --help, -h  Display this help text
+"#,
+		p_name = p_name);
+	}
+	
+	fn parser_f (e :getopts::Fail) -> ExitCode {
+		eprint!("{}", e);
+		exitcode::BAD_ARG
+	}
+	
+	/// Prints the variables of the offload, and its wrapper if it is used
+	fn print_offload (offload :&Offload, wrapper :bool) {
+		let wrapped = wrapper && !offload.wrapper.is_empty();
+		tear_if! { offload.unset.is_empty() && offload.set.is_empty() && !wrapped,
+			println!("  (Nothing)");
+		}
+		
+		for var in &offload.unset {
+			println!("  unset {}", var);
+		}
+		for (var, value) in &offload.set {
+			println!("  {}={}", var, util::shell_escape(value));
+		}
+		if wrapped {
+			println!("  Wrapper: {}", offload.wrapper.join(" "));
+		}
+	}
+	
+	/// Prints the variables of the offload that are set in the environment, and which source
+	/// wins: the environment, or the configuration as described by `config_wins`
+	fn print_env_winners (offload :&Offload, env :&BTreeMap<String, String>, precedence :Precedence, config_wins :&str) {
+		let vars = offload.unset.iter().chain(offload.set.iter().map(|(k, _)| k));
+		for var in vars {
+			if let Some(value) = env.get(var) {
+				let winner = match precedence {
+					Precedence::User => "the environment wins",
+					Precedence::Config => config_wins,
+				};
+				println!("  {}={} is set in the environment: {}", var, util::shell_escape(value), winner);
+			}
+		}
+	}
+	
+	let mut parser = getopts::Options::new();
+	parser.parsing_style(getopts::ParsingStyle::StopAtFirstFree);
+	parser.optflag("h", "help", "");
+	let opts = tear! { parser.parse(args) => parser_f };
+	
+	tear_if! { opts.opt_present("help") || opts.free.is_empty(),
+		print_help(p_name);
+		exitcode::OK
+	}
+	let line = opts.free.join(" ");
+	
+	let conf = tear! { config::load_config() => |e :config::Error| {
+		print_error(&e);
+		exitcode::FAIL
+	}};
+	
+	// The same code path as preexec
+	let matcher = Matcher::new(&conf);
+	let matches = app::matches_command(&conf, &matcher, &Lookup::current(), &line);
+	
+	println!("Command line: {}", line);
+	println!("Commands:");
+	tear_if! { matches.commands.is_empty(), println!("  (None)"); exitcode::OK }
+	for (i, c) in matches.commands.iter().enumerate() {
+		println!("{}. {}", i + 1, c.command.text);
+		println!("   Words: {}", c.command.words.iter().map(|w| util::shell_escape(w)).collect::<Vec<_>>().join(" "));
+		match c.argv.first() {
+			Some(p) if c.excluded => println!("   Program: {} (excluded by the 'exclude' key)", p),
+			Some(p) => println!("   Program: {}", p),
+			None => println!("   Program: (none)"),
+		}
+	}
+	
+	println!();
+	println!("Rules:");
+	tear_if! { matches.outcomes.is_empty(), println!("  (None defined)"); exitcode::OK }
+	for (rule, outcome) in &matches.outcomes {
+		let name = rule.name.as_deref().unwrap_or_default();
+		let won = matches.winner().is_some_and(|m| std::ptr::eq(m.rule, *rule));
+		match outcome {
+			Outcome::Matched(i) if won => println!("- {}: matches command {} (used)", name, i + 1),
+			Outcome::Matched(i) => println!("- {}: matches command {}", name, i + 1),
+			Outcome::Excluded(i) => println!("- {}: command {} is excluded by the rule", name, i + 1),
+			Outcome::NoMatch => println!("- {}: no match", name),
+			Outcome::Disabled => println!("- {}: disabled", name),
+		}
+	}
+	if !matcher.invalid.is_empty() {
+		println!("Invalid patterns, which never match:");
+		for p in &matcher.invalid {
+			println!("- {}", p);
+		}
+	}
+	
+	println!();
+	let env = util::environment();
+	let session = Session::from_env(&env);
+	if let Some(d) = &session.driver {
+		println!("Driver {} is used instead of the configured ones in this shell", d);
+	}
+	match (matches.winner(), matches.offload(&conf, &env)) {
+		(Some(m), Some(Ok(offload))) => {
+			let driver = session.driver(m.rule, &conf.driver);
+			println!("Rule used: {}, driver {} with the {} backend", m.rule.name.as_deref().unwrap_or_default(),
+				driver, conf.backend.name());
+			let precedence = m.rule.precedence(conf.precedence);
+			let from = if m.rule.precedence.is_some() { "set by the rule" } else { "'precedence' key" };
+			println!("Precedence: {} ({})", precedence.name(), from);
+			if let Ok(all) = app::rule_offload(&conf, m.rule, driver) {
+				print_env_winners(&all, &env, precedence, "the rule wins");
+			}
+			if session.off {
+				println!("Offloading is off in this shell, preexec would change nothing");
+			} else {
+				println!("preexec would change:");
+				print_offload(&offload, false);
+				if !offload.wrapper.is_empty() {
+					println!("  (The {} wrapper can't be used by preexec)", offload.wrapper.join(" "));
+				}
+			}
+		},
+		(Some(m), Some(Err(e))) => {
+			println!("Rule used: {}", m.rule.name.as_deref().unwrap_or_default());
+			println!("Failed to find its GPU: {}", e);
+		},
+		_ => println!("No rule matches, preexec would change nothing"),
+	}
+	
+	// run doesn't look at the rules
+	println!();
+	match conf.driver_offload(&conf.driver) {
+		Ok(mut offload) => {
+			println!("`{} run` would change, with driver {}:", p_name, conf.driver);
+			print_env_winners(&offload, &env, conf.precedence, "the 'driver' key wins");
+			if conf.precedence == Precedence::User {
+				offload.keep_env(&env);
+			}
+			print_offload(&offload, true);
+		},
+		Err(e) => println!("`{} run` would fail to find its GPU: {}", p_name, e),
+	}
+	
 	exitcode::OK
 }
 
+/** Lists the detected GPUs from sysfs, or from `xrandr --listproviders`
 
-/// Displays DRI_PRIME values for each GPU by parsing the output of `xrandr --listproviders`
-fn xrandr_subcommand (p_name :&str, args :&[String]) -> ExitCode {
+The sysfs root comes from the `--sysfs` option, then from the configuration
+*/
+fn gpus_subcommand (p_name :&str, args :&[String]) -> ExitCode {
+	use crate::gpu;
+	use std::path::PathBuf;
+	
+	fn print_help (p_name :&str) {
+		print!(
+r#"Usage:
+  {p_name} gpus [options]
+
+Lists the GPUs and the values to use for the 'driver' key.
+
+Options:
+  --help, -h      Display this help text
+  --sysfs <path>  Where sysfs is mounted, defaults to /sys
+  --xrandr        Use the output of `xrandr --listproviders` instead
+"#,
+		p_name = p_name);
+	}
+	
 	fn parser_handler (e :getopts::Fail) -> ExitCode {
-		eprint!("{}", e.to_string());
+		eprint!("{}", e);
 		exitcode::BAD_ARG
 	}
 	
@@ -280,46 +870,77 @@ fn xrandr_subcommand (p_name :&str, args :&[String]) -> ExitCode {
 		exitcode::BAD_IO
 	}
 	
+	/// Prints the DRI_PRIME values for each xrandr provider
+	fn print_xrandr () -> ExitCode {
+		let data = tear! { app::parse_xrandr() => command_failed_handler };
+		
+		println!("DRI_PRIME: description");
+		for (id, desc) in data.iter() {
+			println!("{}: {}", id, desc);
+		}
+		exitcode::OK
+	}
+	
 	// Parser options
 	let mut parser = getopts::Options::new();
-	parser.optflag("h", "help", "Display help");
+	parser.optflag("h", "help", "");
+	parser.optflag("", "xrandr", "");
+	parser.optopt("", "sysfs", "", "");
 	let opts = tear! { parser.parse(args) => parser_handler };
 	
 	// Print help if needed
 	tear_if! { opts.opt_present("help"),
-		println!("Usage: {} xrandr", p_name);
-		println!();
-		println!("Prints the DRI_PRIME values for each GPU based on the output of `xrandr --listproviders`");
+		print_help(p_name);
 		exitcode::OK
 	}
+	tear_if! { opts.opt_present("xrandr"), print_xrandr() }
 	
 	// Collect data
-	let data = tear! { app::parse_xrandr() => command_failed_handler };
+	let root = opts.opt_str("sysfs").map(PathBuf::from)
+		.or_else(|| config::load_config().ok().map(|v| v.sysfs))
+		.unwrap_or_else(|| PathBuf::from(gpu::SYSFS_ROOT));
+	let gpus = tear! { gpu::list(&root) => |e| {
+		eprintln!("Failed to list the GPUs in {:?}:\n{}", root, e);
+		exitcode::BAD_IO
+	}};
+	tear_if! { gpus.is_empty(),
+		println!("No GPUs found in {:?}", root);
+		exitcode::OK
+	}
 	
 	// Output
-	println!("DRI_PRIME: description");
-	for (id, desc) in data.iter() {
-		println!("{}: {}", id, desc);
+	let integrated = gpu::integrated(&gpus).map(|v| v.card);
+	let discrete = gpu::discrete(&gpus).map(|v| v.card);
+	for g in &gpus {
+		let role =
+			if Some(g.card) == integrated { " (integrated)" }
+			else if Some(g.card) == discrete { " (discrete)" }
+			else { "" };
+		println!("card{}{}", g.card, role);
+		println!("  PCI tag: {}", g.pci_tag());
+		println!("  vendor:device: {}", g.vendor_device());
+		println!("  Kernel driver: {}", g.driver.as_deref().unwrap_or("(none)"));
+		println!("  Boot VGA: {}", if g.boot_vga { "yes" } else { "no" });
+		println!("  Nodes: {}{}", g.card_node.to_string_lossy(),
+			g.render_node.as_ref().map(|v| format!(", {}", v.to_string_lossy())).unwrap_or_default());
 	}
-
+	
 	exitcode::OK
 }
 
 /// Display the loaded configuration.
 fn show_config_subcommand () -> ExitCode {
+	use std::path::PathBuf;
+	use crate::rule::{Rule, Precedence};
+	use crate::prefix::Prefix;
+	use crate::matcher::{Field, Origin};
+	
 	fn handle_config_error(e :config::Error) -> ExitCode {
 		use config::Error::*;
-		fn print_source (e :config::Error) {
-			use std::error::Error;
-			if let Some(ee) = e.source() {
-				eprintln!("{}", ee)
-			}
-		}
 		
 		match e {
-			FindFileF | ParseF(..) => {
-				eprintln!("{}", e);
-				print_source(e);
+			FindFileF | ParseF(..) | GpuListF(..) | NoGpuF(..) | VarNameF(..) => {
+				print_error(&e);
 				exitcode::FAIL
 			},
 			NoFileF(p) => {
@@ -328,14 +949,13 @@ fn show_config_subcommand () -> ExitCode {
 				exitcode::BAD_IO
 			},
 			ReadFileF(..) => {
-				eprintln!("{}", e);
-				print_source(e);
+				print_error(&e);
 				exitcode::BAD_IO
 			},
 		}
 	}
 	
-	fn print_matches (r#match :Option<Vec<String>>) {
+	fn print_matches (r#match :Option<Vec<String>>, matcher :&Matcher) {
 		let matches = tear! { r#match => |_| {
 			println!("No commands matches defined in the 'match' key");
 		}};
@@ -345,16 +965,111 @@ fn show_config_subcommand () -> ExitCode {
 			println!("  (None defined)");
 		}
 
-		// Print the list of matches
-		for m in matches {
-			// Check if it compiles
-			let compiles = Regex::new(&m).is_ok();
-			let compile_ok = if compiles { "" } else { " (invalid regex)" };
-			println!("- {}{}", m, compile_ok);
+		// Print the list of matches, which are the first rule
+		for (i, m) in matches.iter().enumerate() {
+			print_pattern("- ", m, matcher, Origin { rule: Some(0), field: Field::Match, index: i });
 		}
 	}
 	
-	fn print_aliases (alias :Option<Vec<String>>) {
+	fn print_excludes (exclude :Option<Vec<String>>, matcher :&Matcher) {
+		let excludes = tear! { exclude => |_| {
+			println!("No commands excluded by the 'exclude' key");
+		}};
+		
+		println!("Commands excluded (glob):");
+		tear_if! { excludes.is_empty(),
+			println!("  (None defined)");
+		}
+		for (i, e) in excludes.iter().enumerate() {
+			print_pattern("- ", e, matcher, Origin { rule: None, field: Field::Exclude, index: i });
+		}
+	}
+	
+	/// Prints the pattern, and the error of the matcher if it doesn't compile
+	fn print_pattern (indent :&str, p :&str, matcher :&Matcher, origin :Origin) {
+		match matcher.error(origin) {
+			None => println!("{}{}", indent, p),
+			Some(e) => {
+				println!("{}{} (invalid, index {})", indent, p, origin.index);
+				let pad = indent.len() - indent.trim_start().len() + 2;
+				for line in e.lines() {
+					println!("{}{}", " ".repeat(pad), line);
+				}
+			},
+		}
+	}
+	
+	/// `offset` is the index of the first `[[rule]]` table among the rules of the matcher
+	fn print_rules (rules :Option<Vec<Rule>>, matcher :&Matcher, offset :usize) {
+		let rules = tear! { rules => |_| {
+			println!("No rules defined by '[[rule]]' tables");
+		}};
+		
+		println!("Rules:");
+		tear_if! { rules.is_empty(),
+			println!("  (None defined)");
+		}
+		
+		for (i, r) in rules.iter().enumerate() {
+			let origin = |field, index| Origin { rule: Some(offset + i), field, index };
+			let disabled = if r.enabled { "" } else { " (disabled)" };
+			println!("- {}{}", r.display_name(i), disabled);
+			if let Some(d) = &r.driver {
+				println!("  Driver: {}", d);
+			}
+			if r.priority != 0 {
+				println!("  Priority: {}", r.priority);
+			}
+			if let Some(p) = r.precedence {
+				println!("  Precedence: {}", p.name());
+			}
+			if !r.match_.is_empty() || r.program.is_none() {
+				println!("  Matches ({}):", r.kind.name());
+			}
+			if r.match_.is_empty() && r.program.is_none() {
+				println!("    (None defined)");
+			}
+			for (j, m) in r.match_.iter().enumerate() {
+				print_pattern("    - ", m, matcher, origin(Field::Match, j));
+			}
+			if let Some(p) = &r.program {
+				println!("  Program: {}", p);
+				for (j, a) in r.args.iter().enumerate() {
+					print_pattern("    Argument: ", a, matcher, origin(Field::Args, j));
+				}
+			}
+			if !r.exclude.is_empty() {
+				println!("  Excludes ({}):", r.kind.name());
+			}
+			for (j, e) in r.exclude.iter().enumerate() {
+				print_pattern("    - ", e, matcher, origin(Field::Exclude, j));
+			}
+			for (k, v) in &r.env {
+				println!("  Sets {}={}", k, v);
+			}
+			for k in &r.unset {
+				println!("  Unsets {}", k);
+			}
+		}
+	}
+	
+	fn print_preexec (preexec :Option<Metadata<config::PreexecFrom, PathBuf>>) {
+		match preexec {
+			Some(Metadata(from, p)) => {
+				let missing = if p.exists() { "" } else { ", but it doesn't exist" };
+				println!("Preexec path: {} ({}{})", p.to_string_lossy(), from.reason(), missing);
+			},
+			None => {
+				println!("Preexec path: not found, searched:");
+				for p in file::bash_preexec_paths() {
+					println!("- {}", p.to_string_lossy());
+				}
+			},
+		}
+		println!("  (An already loaded bash-preexec or ble.sh is used first)");
+	}
+	
+	fn print_aliases (alias :Option<Vec<config::Alias>>) {
 		if let Some(aliases) = alias {
 			println!("Aliases:");
 			if aliases.is_empty() {
@@ -362,7 +1077,10 @@ fn show_config_subcommand () -> ExitCode {
 			} else {
 				// Print them
 				for a in aliases {
-					println!("- {}", a)
+					match a.driver() {
+						Some(d) => println!("- {} (driver: {})", a.command(), d),
+						None => println!("- {}", a.command()),
+					}
 				}
 			}
 		} else {
@@ -370,6 +1088,25 @@ fn show_config_subcommand () -> ExitCode {
 		}
 	}
 	
+	fn print_prefixes (prefix :Option<Vec<Prefix>>) {
+		match &prefix {
+			Some(_) => println!("Prefixes skipped when matching:"),
+			None => println!("Prefixes skipped when matching ('prefix' not set):"),
+		}
+		let prefixes = prefix.unwrap_or_else(crate::prefix::defaults);
+		if prefixes.is_empty() {
+			println!("  (None defined)");
+		}
+		for p in prefixes {
+			let assignments = if p.assignments { ", then VAR=value" } else { "" };
+			if p.value_options.is_empty() {
+				println!("- {}{}", p.command, assignments);
+			} else {
+				println!("- {} (options with a value: {}){}", p.command, p.value_options.join(" "), assignments);
+			}
+		}
+	}
+	
 	// Load config
 	let Metadata(meta, config) = tear! {
 		config::load_config_meta() => handle_config_error
@@ -387,17 +1124,53 @@ fn show_config_subcommand () -> ExitCode {
 	
 	println!();
 	
-	// Handle 'preexec'
-	if let Some(preexec) = config.preexec {
-		println!("Preexec path: {}", preexec);
+	// Handle 'hook' and 'preexec'
+	let hook = config.hook.unwrap_or(config::Hook::BashPreexec);
+	println!("Bash hook: {}", hook.name());
+	if hook == config::Hook::BashPreexec {
+		print_preexec(config.preexec_path());
+	}
+	if let Ok(v) = std::env::var(HOOK_VAR) {
+		println!("Hook loaded in this shell: {}", v);
+	}
+	
+	// Handle 'backend' key
+	let backend = config.backend.unwrap_or(Backend::Auto);
+	if backend == Backend::Auto {
+		println!("Backend: {} (auto-detected)", backend.resolve().name());
+	} else {
+		println!("Backend: {}", backend.name());
 	}
 	
 	// Handle 'driver' key
-	let driver = config.driver.unwrap_or_else(|| "1 ('driver' not set)".to_string());
-	println!("Default GPU id: {}", driver);
+	let driver = config.driver.clone().unwrap_or_default();
+	let unset = if config.driver.is_none() { " ('driver' not set)" } else { "" };
+	match driver.resolve(&*backend.resolve(), &config.sysfs_root()) {
+		Ok(v) if v == driver.to_string() => println!("Default GPU id: {}{}", driver, unset),
+		Ok(v) => println!("Default GPU id: {} ({}){}", driver, v, unset),
+		Err(e) => println!("Default GPU id: {} ({}){}", driver, e, unset),
+	}
+	if let Some(sysfs) = &config.sysfs {
+		println!("Sysfs root: {}", sysfs);
+	}
+	
+	// Handle 'precedence' key
+	match config.precedence.unwrap_or_default() {
+		Precedence::User => println!("Precedence: user (variables set in the environment keep their value)"),
+		Precedence::Config => println!("Precedence: config (the configuration overrides the environment)"),
+	}
 
-	// Handle 'match' and 'alias' keys
-	print_matches(config.match_);
+	// Handle 'match', 'exclude', 'rule', 'prefix' and 'alias' keys, with freshly compiled patterns
+	let matcher = Matcher::new(&config.clone().set_defaults());
+	let offset = if config.match_.is_some() { 1 } else { 0 };
+	print_matches(config.match_, &matcher);
+	print_excludes(config.exclude, &matcher);
+	println!();
+	print_rules(config.rules, &matcher, offset);
+	println!("Strategy: {} (among matching rules of the same priority)",
+		config.strategy.unwrap_or_default().name());
+	println!();
+	print_prefixes(config.prefix);
 	println!();
 	print_aliases(config.alias);
 
@@ -413,6 +1186,222 @@ fn reload_aliases_subcommand () -> ExitCode {
 		exitcode::OK
 	}
 
-	tear! { app::reload_aliases(identity) };
+	tear! { app::reload_aliases(identity, Shell::from_env()) };
+	exitcode::OK
+}
+
+/** Changes the state of the shell: `off`, `on` or `set-driver`
+
+The change is made by the preexec hook, so there is nothing left to do when it ran. Otherwise,
+the shell code is printed for the user to evaluate.
+*/
+fn session_subcommand (p_name :&str, subcommand :&str, args :&[String]) -> ExitCode {
+	use std::io::IsTerminal;
+	
+	fn print_help (p_name :&str) {
+		print!(
+r#"Usage:
+  {p_name} off
+  {p_name} on
+  {p_name} set-driver <driver>
+  {p_name} set-driver --reset
+
+Disables or enables offloading in this shell, or makes the commands matched in
+this shell use the driver, instead of the one configured. See `{p_name} status`.
+They need the preexec hook to change the shell, otherwise evaluate their output,
+eg. `eval "$( {p_name} off )"`.
+"#,
+		p_name = p_name);
+	}
+	
+	tear_if! { args.iter().any(|a| a == "--help" || a == "-h"),
+		print_help(p_name);
+		exitcode::OK
+	}
+	let change = tear! { Change::from_args(subcommand, args) => |_| {
+		eprintln!("Invalid arguments, see `{} {} --help`", p_name, subcommand);
+		exitcode::BAD_ARG
+	}};
+	tear! { change.check(config::load_config().ok().as_ref()) => |e :config::Error| {
+		print_error(&e);
+		exitcode::BAD_ARG
+	}};
+	
+	let applied = change.is_applied(&util::environment());
+	if app::hook_ran() {
+		// Done by the preexec hook, unless it couldn't see the command
+		tear_if! { !applied,
+			eprintln!("The preexec hook didn't understand the command line, write `{} {}` alone",
+				p_name, subcommand);
+			exitcode::FAIL
+		}
+	} else {
+		let sh = Shell::from_env();
+		print!("{}", change.code(sh));
+		if std::io::stdout().is_terminal() {
+			eprintln!("The preexec hook isn't loaded, evaluate the output to change the shell");
+		}
+	}
+	exitcode::OK
+}
+
+/// Shows the state of the current shell
+fn status_subcommand (p_name :&str, args :&[String]) -> ExitCode {
+	fn print_help (p_name :&str) {
+		print!(
+r#"Usage:
+  {p_name} status
+
+Shows whether offloading is on in this shell, and the driver it uses.
+"#,
+		p_name = p_name);
+	}
+	
+	tear_if! { !args.is_empty(),
+		print_help(p_name);
+		if args.iter().any(|a| a == "--help" || a == "-h") { exitcode::OK } else { exitcode::BAD_ARG }
+	}
+	
+	match std::env::var(shell::SHELL_VAR) {
+		Ok(sh) => match std::env::var(HOOK_VAR) {
+			Ok(hook) => println!("Shell: {} (hook: {})", sh, hook),
+			Err(_) => println!("Shell: {}", sh),
+		},
+		Err(_) => println!("Shell: not initialized, see `{} init --help`", p_name),
+	}
+	
+	let session = Session::from_env(&util::environment());
+	if session.off {
+		println!("Offloading: off (`{} on` to enable it)", p_name);
+	} else {
+		println!("Offloading: on");
+	}
+	match &session.driver {
+		Some(d) => println!("Driver: {} (`{} set-driver --reset` for the configured ones)", d, p_name),
+		None => println!("Driver: from the configuration"),
+	}
+	exitcode::OK
+}
+
+/// Writes the shims of the configured commands
+fn shims_subcommand (p_name :&str, args :&[String]) -> ExitCode {
+	fn print_help (p_name :&str) {
+		print!(
+r#"Usage:
+  {p_name} shims [options]
+
+Writes a wrapper for each command named by the rules and the 'alias' key, which
+runs the real program with the variables of its rule. With their directory first
+in PATH, the programs started outside of the shell are offloaded too, eg. from a
+desktop menu. `{p_name} init` adds it to PATH in the shell.
+Run it again when the configuration changes.
+
+Options:
+  --help, -h  Display this help text
+  --link      Write symlinks to {p_name} instead of scripts
+"#,
+		p_name = p_name);
+	}
+	
+	fn parser_f (e :getopts::Fail) -> ExitCode {
+		eprint!("{}", e);
+		exitcode::BAD_ARG
+	}
+	
+	let mut parser = getopts::Options::new();
+	parser.optflag("h", "help", "");
+	parser.optflag("", "link", "");
+	let opts = tear! { parser.parse(args) => parser_f };
+	tear_if! { opts.opt_present("help") || !opts.free.is_empty(),
+		print_help(p_name);
+		if opts.opt_present("help") { exitcode::OK } else { exitcode::BAD_ARG }
+	}
+	
+	let config = tear! { config::load_config() => |e :config::Error| {
+		print_error(&e);
+		exitcode::FAIL
+	}};
+	let written = tear! { shim::write(&config, opts.opt_present("link")) => |e :shim::ws::Error| {
+		print_error(&e);
+		exitcode::BAD_IO
+	}};
+	
+	let dir = written.dir.to_string_lossy();
+	println!("Shims in {}:", dir);
+	if written.written.is_empty() {
+		println!("  (None, the rules have no plain names)");
+	}
+	for c in &written.written {
+		println!("- {}", c);
+	}
+	if !written.removed.is_empty() {
+		println!("Removed the shims of: {}", written.removed.join(", "));
+	}
+	if !written.skipped.is_empty() {
+		println!("Skipped, as they aren't shims: {}", written.skipped.join(", "));
+	}
+	
+	println!();
+	println!("To offload the programs started outside of the shell, add this to ~/.profile:");
+	println!("  export PATH={}:\"$PATH\"", util::shell_escape(&dir));
 	exitcode::OK
 }
+
+/// Runs the command of a shim, or of a link to switchable, with the variables of its rule.
+/// Configuration errors are reported, but the command still runs
+fn shim_exec (args :&[String]) -> ExitCode {
+	use std::process::Command;
+	use std::os::unix::process::CommandExt;
+	use crate::backend::Offload;
+	
+	let (command, args) = tear! { args.split_first() => |_| exitcode::MISSING_ARG };
+	let program = tear! { shim::find_real(command) => |_| {
+		eprintln!("{}: Could not find `{}` in PATH, besides its shim", INIT_NAME, command);
+		exitcode::FAIL
+	}};
+	
+	let env = util::environment();
+	let offload = match config::load_config() {
+		Ok(conf) => {
+			let matcher = Matcher::load(&conf);
+			let offload = shim::offload(&conf, &matcher, command, args, &env);
+			matcher.save();
+			match offload {
+				Some(Ok(offload)) => offload,
+				Some(Err(e)) => {
+					eprintln!("{}: {}", INIT_NAME, e);
+					Offload::default()
+				},
+				None => Offload::default(),
+			}
+		},
+		Err(config::Error::NoFileF(_)) => Offload::default(),
+		Err(e) => {
+			eprintln!("{}: {}", INIT_NAME, e);
+			Offload::default()
+		},
+	};
+	
+	// The program keeps its name, unless a wrapper runs it
+	let mut cmd = match offload.wrapper.split_first() {
+		Some((wrapper, wrapper_args)) => {
+			let mut cmd = Command::new(wrapper);
+			cmd.args(wrapper_args).arg(&program);
+			cmd
+		},
+		None => {
+			let mut cmd = Command::new(&program);
+			cmd.arg0(command);
+			cmd
+		},
+	};
+	for var in &offload.unset {
+		cmd.env_remove(var);
+	}
+	cmd.envs(offload.set.iter().map(|(k, v)| (k, v)));
+	let e = cmd.args(args).exec();
+	
+	// exec only returns on failure
+	eprintln!("{}: Failed to execute '{}': {}", INIT_NAME, program.to_string_lossy(), e);
+	exitcode::FAIL
+}
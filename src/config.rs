@@ -4,8 +4,8 @@
 ```rust
 use crate::config;
 let config = match config::load_config() {
-	Ok(v) => v,
-	Err(e) => handle_config_error(e),
+    Ok(v) => v,
+    Err(e) => handle_config_error(e),
 }
 ```
 
@@ -15,10 +15,14 @@ and would need to be quoted as r#match, which is annoying
 */
 
 prelude!();
-use crate::file;
-use std::io;
-use std::{path::PathBuf, fs::File, io::Read};
-use serde::Deserialize;
+use crate::{file, gpu};
+use crate::rule::{Rule, Strategy, Precedence};
+use crate::prefix::{self, Prefix};
+use crate::backend::{Backend, GpuBackend, Offload};
+use std::{io, fmt};
+use std::convert::TryFrom;
+use std::{path::{Path, PathBuf}, fs::File, io::Read};
+use serde::{Serialize, Deserialize};
 use self::Error::*;
 
 /// Configuration metadata
@@ -27,42 +31,347 @@ pub struct Meta {
 	pub location :file::Location,
 }
 
+/// Identifies a version of the configuration file, so that caches can be invalidated and the
+/// daemon can reload it
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Stamp {
+	pub path :PathBuf,
+	/// Modification time as seconds and nanoseconds since the epoch
+	pub modified :Option<(u64, u32)>,
+	/// Hash of the contents
+	pub hash :u64,
+}
+
+impl Stamp {
+	fn new (path :&Path, contents :&str) -> Stamp {
+		use std::collections::hash_map::DefaultHasher;
+		use std::hash::{Hash, Hasher};
+		
+		let mut hasher = DefaultHasher::new();
+		contents.hash(&mut hasher);
+		Stamp { path: path.to_path_buf(), modified: Stamp::modified_of(path), hash: hasher.finish() }
+	}
+	
+	/// Modification time of the file as stored in stamps
+	fn modified_of (path :&Path) -> Option<(u64, u32)> {
+		use std::time::UNIX_EPOCH;
+		path.metadata().and_then(|m| m.modified()).ok()
+			.and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+			.map(|d| (d.as_secs(), d.subsec_nanos()))
+	}
+	
+	/// Whether the configuration file is still the same, judging by its path and modification time
+	pub fn is_current (&self) -> bool {
+		file::find_config_file().is_some_and(|p| p == self.path)
+			&& self.modified.is_some() && Stamp::modified_of(&self.path) == self.modified
+	}
+}
+
 /// Shortcut for `Option<T>`
 type O<T> = Option<T>;
 
+/// How bash gets its preexec and precmd hooks
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Hook {
+	/// Source bash-preexec from the `preexec` path
+	BashPreexec,
+	/// Use our own DEBUG trap and PROMPT_COMMAND hooks
+	Builtin,
+}
+
+impl Hook {
+	/// Name as written in the config
+	pub fn name (self) -> &'static str {
+		match self {
+			Hook::BashPreexec => "bash-preexec",
+			Hook::Builtin => "builtin",
+		}
+	}
+}
+
+/// Where the bash-preexec path comes from
+#[derive(Clone, Copy, Debug)]
+pub enum PreexecFrom {
+	/// The `preexec` key
+	Config,
+	/// The first existing file in `file::bash_preexec_paths`
+	Search,
+}
+
+impl PreexecFrom {
+	/// Human readable reason for the choice
+	pub fn reason (self) -> &'static str {
+		match self {
+			PreexecFrom::Config => "set by the 'preexec' key",
+			PreexecFrom::Search => "found in a known location",
+		}
+	}
+}
+
+/// The `driver` key: which GPU to use
+#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(try_from = "RawDriver")]
+pub enum Driver {
+	/// eg. `1`, an index as understood by `DRI_PRIME`
+	Index(u32),
+	/// eg. `pci-0000_01_00_0`
+	PciTag(String),
+	/// eg. `1002:687f`
+	VendorDevice(u16, u16),
+	/// The GPU that isn't used for the boot display
+	Discrete,
+	/// The GPU used for the boot display
+	Integrated,
+	/// Anything else, given as is to the backend
+	Other(String),
+}
+
+/// What TOML gives us for `driver`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawDriver {
+	Int(i64),
+	Str(String),
+}
+
+impl TryFrom<RawDriver> for Driver {
+	type Error = String;
+
+	fn try_from (v :RawDriver) -> std::result::Result<Self, Self::Error> {
+		match v {
+			RawDriver::Int(i) => u32::try_from(i)
+				.map(Driver::Index)
+				.map_err(|_| format!("invalid driver {}, expected a non-negative integer", i)),
+			RawDriver::Str(s) => Ok(Driver::parse(&s)),
+		}
+	}
+}
+
+impl Default for Driver {
+	fn default () -> Self {
+		Driver::Index(1)
+	}
+}
+
+impl fmt::Display for Driver {
+	fn fmt (&self, f :&mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Driver::Index(i) => write!(f, "{}", i),
+			Driver::PciTag(s) | Driver::Other(s) => f.write_str(s),
+			Driver::VendorDevice(v, d) => write!(f, "{:04x}:{:04x}", v, d),
+			Driver::Discrete => f.write_str("discrete"),
+			Driver::Integrated => f.write_str("integrated"),
+		}
+	}
+}
+
+impl Driver {
+	/// Recognize the notation used in the string
+	pub fn parse (s :&str) -> Driver {
+		fn hex (s :&str) -> Option<u16> {
+			tear_if! { s.len() != 4, None }
+			u16::from_str_radix(s, 16).ok()
+		}
+		
+		let s = s.trim();
+		if let Ok(i) = s.parse() {
+			return Driver::Index(i);
+		}
+		match s {
+			"discrete" => Driver::Discrete,
+			"integrated" => Driver::Integrated,
+			_ if s.starts_with("pci-") => Driver::PciTag(s.to_string()),
+			_ => {
+				let mut ids = s.splitn(2, ':').map(hex);
+				match (ids.next().flatten(), ids.next().flatten()) {
+					(Some(v), Some(d)) => Driver::VendorDevice(v, d),
+					_ => Driver::Other(s.to_string()),
+				}
+			},
+		}
+	}
+	
+	/// Whether it asks for the integrated GPU, by name or as `DRI_PRIME=0`
+	pub fn is_integrated (&self) -> bool {
+		matches!(self, Driver::Integrated | Driver::Index(0))
+	}
+	
+	/// The GPU value to give to the backend. Other values are given as is, and indices too if the
+	/// backend takes them, while the rest are resolved against the GPUs found under the sysfs root
+	pub fn resolve (&self, backend :&dyn GpuBackend, sysfs :&Path) -> Result<String> {
+		match self {
+			Driver::Other(v) => return Ok(v.clone()),
+			Driver::Index(i) => if let Some(id) = backend.index(*i) {
+				return Ok(id);
+			},
+			_ => {},
+		}
+		
+		let gpus = terror! { gpu::list(sysfs) => |e| GpuListF(self.clone(), e) };
+		let found = match self {
+			Driver::Discrete => gpu::discrete(&gpus),
+			Driver::Integrated | Driver::Index(0) => gpu::integrated(&gpus),
+			// Like DRI_PRIME, 1 is the first GPU that isn't the default one
+			Driver::Index(i) => gpu::discrete(&gpus)
+				.and_then(|_| gpus.iter().filter(|v| !v.boot_vga).nth(*i as usize - 1)),
+			Driver::PciTag(tag) => gpus.iter().find(|v| v.pci_tag() == *tag),
+			Driver::VendorDevice(vendor, device) =>
+				gpus.iter().find(|v| v.vendor == *vendor && v.device == *device),
+			Driver::Other(_) => None,
+		};
+		let found = terror! { found => |_| NoGpuF(self.clone()) };
+		Ok(backend.gpu_id(found))
+	}
+	
+	/// What the backend changes so that a program runs on this GPU
+	pub fn offload (&self, backend :&dyn GpuBackend, sysfs :&Path) -> Result<Offload> {
+		let gpu = terror! { self.resolve(backend, sysfs) };
+		if self.is_integrated() {
+			Ok(backend.integrated(&gpu))
+		} else {
+			Ok(backend.offload(&gpu))
+		}
+	}
+}
+
+/// An entry of the `alias` key
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Alias {
+	/// Just the command, which uses the `driver` key
+	Command(String),
+	/// A command with its own driver, eg. `{ command = "blender", driver = "2" }`
+	Table {
+		command :String,
+		driver :O<Driver>,
+	},
+}
+
+impl Alias {
+	/// The command to alias
+	pub fn command (&self) -> &str {
+		match self {
+			Alias::Command(c) | Alias::Table { command: c, .. } => c,
+		}
+	}
+	
+	/// The driver specific to this alias, if any
+	pub fn driver (&self) -> Option<&Driver> {
+		match self {
+			Alias::Command(_) => None,
+			Alias::Table { driver, .. } => driver.as_ref(),
+		}
+	}
+}
+
+/// The configured bash-preexec path, or the first of the known locations that exists
+fn preexec_path (configured :Option<&Path>) -> Option<Metadata<PreexecFrom, PathBuf>> {
+	match configured {
+		Some(p) => Some(Metadata(PreexecFrom::Config, p.to_path_buf())),
+		None => file::find_bash_preexec().map(|p| Metadata(PreexecFrom::Search, p)),
+	}
+}
+
 /// The Config before the defaults are applied, mirroring the configuration file
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Clone, Debug)]
 pub struct RawConfig {
-	pub driver :O<String>,
+	pub driver :O<Driver>,
 	#[serde(rename = "match")] // Use 'match' in the config
 	pub match_ :O<Vec<String>>,
-	pub alias :O<Vec<String>>,
+	#[serde(rename = "rule")] // Use '[[rule]]' in the config
+	pub rules :O<Vec<Rule>>,
+	pub exclude :O<Vec<String>>,
+	pub strategy :O<Strategy>,
+	pub precedence :O<Precedence>,
+	pub alias :O<Vec<Alias>>,
+	pub prefix :O<Vec<Prefix>>,
 	pub preexec :O<String>,
+	pub hook :O<Hook>,
+	pub backend :O<Backend>,
+	pub sysfs :O<String>,
+	/// Which file it was read from, and when
+	#[serde(skip)]
+	pub stamp :O<Stamp>,
 }
 
 /// The consumable configuration where we limit the amount of optional values.
 #[derive(Debug)]
 pub struct FullConfig {
-	pub driver :String,
-	pub match_ :Vec<String>,
-	pub alias :Vec<String>,
+	pub driver :Driver,
+	/// The `match` key as the first rule, then the `[[rule]]` tables. All have a name
+	pub rules :Vec<Rule>,
+	/// Globs of programs no rule matches
+	pub exclude :Vec<String>,
+	/// Which rule wins among matching rules of the same priority
+	pub strategy :Strategy,
+	/// Whether variables set in the environment win, unless a rule says otherwise
+	pub precedence :Precedence,
+	pub alias :Vec<Alias>,
+	/// Commands skipped when matching, the defaults if `prefix` isn't set
+	pub prefix :Vec<Prefix>,
+	/// The configured bash-preexec path, see `preexec_path` for the one to use
 	pub preexec :Option<PathBuf>,
+	pub hook :Hook,
+	pub backend :Box<dyn GpuBackend>,
+	pub sysfs :PathBuf,
+	pub stamp :Option<Stamp>,
+}
+
+impl FullConfig {
+	/// The bash-preexec path to use, see `RawConfig::preexec_path`. The known locations are
+	/// only searched when this is called, not on every load
+	pub fn preexec_path (&self) -> Option<Metadata<PreexecFrom, PathBuf>> {
+		preexec_path(self.preexec.as_deref())
+	}
+	
+	/// What the configured backend changes so that a program runs on the GPU
+	pub fn driver_offload (&self, driver :&Driver) -> Result<Offload> {
+		driver.offload(&*self.backend, &self.sysfs)
+	}
+	
+	/// The commands of the `alias` key
+	pub fn alias_commands (&self) -> Vec<String> {
+		self.alias.iter().map(|a| a.command().to_string()).collect()
+	}
 }
 
 impl RawConfig {
+	/// The bash-preexec path to use. The configured path always wins, even if it doesn't
+	/// exist, otherwise we search the known locations.
+	pub fn preexec_path (&self) -> Option<Metadata<PreexecFrom, PathBuf>> {
+		preexec_path(self.preexec.as_deref().map(Path::new))
+	}
+	
+	/// Where sysfs is, to detect the GPUs
+	pub fn sysfs_root (&self) -> PathBuf {
+		PathBuf::from(self.sysfs.as_deref().unwrap_or(gpu::SYSFS_ROOT))
+	}
+	
 	/// Creates a valid Config object from a RawConfig object by setting defaults
 	pub fn set_defaults (self) -> FullConfig {
-		use dirs::home_dir;
+		let preexec = self.preexec.as_ref().map(PathBuf::from);
+		let sysfs = self.sysfs_root();
 		
-		let preexec = self.preexec
-			.map(PathBuf::from)
-			.or_else(|| home_dir().map(|v| v.join(".bash-preexec.sh")));
+		let match_rule = self.match_.map(Rule::from_match_key);
+		let rules = self.rules.unwrap_or_default().into_iter()
+			.enumerate()
+			.map(|(i, mut r)| { r.name = Some(r.display_name(i)); r });
+		let rules = match_rule.into_iter().chain(rules).collect();
 		
 		FullConfig {
-			driver: self.driver.unwrap_or_else(|| "1".to_string()),
-			match_: self.match_.unwrap_or_default(),
+			driver: self.driver.unwrap_or_default(),
+			rules,
+			exclude: self.exclude.unwrap_or_default(),
+			strategy: self.strategy.unwrap_or_default(),
+			precedence: self.precedence.unwrap_or_default(),
 			alias: self.alias.unwrap_or_default(),
+			prefix: self.prefix.unwrap_or_else(prefix::defaults),
 			preexec,
+			hook: self.hook.unwrap_or(Hook::BashPreexec),
+			backend: self.backend.unwrap_or(Backend::Auto).resolve(),
+			sysfs,
+			stamp: self.stamp,
 		}
 	}
 }
@@ -78,6 +387,12 @@ pub enum Error {
 	ReadFileF(PathBuf, #[source] io::Error),
 	#[error("Failed to parse configuration file {0:?}")]
 	ParseF(PathBuf, #[source] toml::de::Error),
+	#[error("Failed to list the GPUs to find GPU {0}")]
+	GpuListF(Driver, #[source] io::Error),
+	#[error("Could not find GPU {0} among the detected ones, see `switchable gpus`")]
+	NoGpuF(Driver),
+	#[error("Invalid variable name {1:?} in configuration file {0:?}, expected letters, digits and underscores, not starting with a digit")]
+	VarNameF(PathBuf, String),
 }
 
 /// Constructor for Io errors that knows if the file doesn't exist
@@ -100,10 +415,25 @@ fn load_config_file (path :PathBuf) -> Result<RawConfig> {
 	terror! { file.read_to_string(&mut str) => |e| ReadFileF(path, e) };
 	
 	// Parse string as a config
-	let config :RawConfig = terror! { toml::from_str(&str) => |e| ParseF(path, e) };
+	let stamp = Stamp::new(&path, &str);
+	let mut config :RawConfig = terror! { toml::from_str(&str) => |e| ParseF(path, e) };
+	config.stamp = Some(stamp);
+	
+	// The variables of the rules end up in shell code
+	let mut vars = config.rules.iter().flatten().flat_map(|r| r.env.keys().chain(&r.unset));
+	if let Some(var) = vars.find(|v| !is_var_name(v)) {
+		return Err(VarNameF(path, var.clone()));
+	}
 	Ok(config)
 }
 
+/// Whether the string is a valid shell variable name, like `[A-Za-z_][A-Za-z0-9_]*`
+fn is_var_name (s :&str) -> bool {
+	let mut chars = s.chars();
+	chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+		&& chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
 /// Returns the RawConfig loaded from disk
 pub fn load_config () -> Result<FullConfig> {
 	let path = terror! { file::find_config_file() => |_| FindFileF };
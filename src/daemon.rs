@@ -0,0 +1,155 @@
+/*! The optional daemon, which keeps the configuration loaded
+
+`switchable daemon` listens on `$XDG_RUNTIME_DIR/switchable.sock`. When it is running,
+`switchable preexec` sends it the command line instead of loading the configuration and
+compiling the patterns itself, and prints its answer. When it isn't, or it fails to answer,
+preexec does the work itself as usual.
+
+The protocol is one JSON `Request` line from the client, and one JSON `Response` line back.
+The request has the environment and working directory of the shell, so that its programs are
+looked up in its PATH rather than the daemon's.
+The daemon reloads the configuration when its file changes.
+*/
+
+prelude!();
+use crate::config::{self, FullConfig};
+use crate::matcher::Matcher;
+use crate::file;
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long the client waits for the daemon before doing the work itself
+const CLIENT_TIMEOUT :Duration = Duration::from_millis(500);
+
+/// What preexec asks the daemon
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Request {
+	/// Name of the shell, eg. `bash`
+	pub shell :String,
+	/// The command line about to be run
+	pub line :String,
+	/// The environment of the client, which variables may keep their value, and where its
+	/// programs are looked up
+	#[serde(default)]
+	pub env :BTreeMap<String, String>,
+	/// The working directory of the client, where its relative paths are looked up
+	#[serde(default)]
+	pub cwd :Option<PathBuf>,
+}
+
+/// The answer of the daemon
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Response {
+	/// Shell code for preexec to print, or `None` if the client should do the work itself,
+	/// eg. because the configuration fails to load
+	pub output :Option<String>,
+}
+
+/// Module for `serve`
+pub mod sv {
+	prelude!();
+	use std::{io, path::PathBuf, result};
+
+	#[derive(ThisError, Debug)]
+	pub enum Error {
+		#[error("Failed to find the socket path because XDG_RUNTIME_DIR is not set")]
+		NoRuntimeDirF,
+		#[error("A daemon is already listening on {0:?}")]
+		AlreadyRunningF(PathBuf),
+		#[error("Failed to listen on {0:?}")]
+		BindF(PathBuf, #[source] io::Error),
+	}
+
+	pub type Result<T> = result::Result<T, self::Error>;
+}
+
+/// The loaded configuration, reloaded when the file changes
+struct State {
+	loaded :Option<(FullConfig, Matcher)>,
+}
+
+impl State {
+	/// The configuration and its patterns, if the configuration loads
+	fn get (&mut self) -> Option<&(FullConfig, Matcher)> {
+		let current = self.loaded.as_ref()
+			.is_some_and(|(c, _)| c.stamp.as_ref().is_some_and(|s| s.is_current()));
+		if !current {
+			self.loaded = config::load_config().ok().map(|c| {
+				let m = Matcher::new(&c);
+				(c, m)
+			});
+		}
+		self.loaded.as_ref()
+	}
+}
+
+/// Binds the socket, replacing it if it was left by a daemon that is gone
+fn bind (path :&Path) -> sv::Result<UnixListener> {
+	use std::os::unix::fs::PermissionsExt;
+	use sv::Error::*;
+
+	if path.exists() {
+		tear_if! { UnixStream::connect(path).is_ok(), Err(AlreadyRunningF(path.to_path_buf())) }
+		let _ = std::fs::remove_file(path);
+	}
+
+	let listener = terror! { UnixListener::bind(path) => |e| BindF(path.to_path_buf(), e) };
+	let perms = std::fs::Permissions::from_mode(0o600);
+	terror! { std::fs::set_permissions(path, perms) => |e| BindF(path.to_path_buf(), e) };
+	Ok(listener)
+}
+
+/// Answers one client
+fn handle<F> (stream :UnixStream, state :&mut State, answer :&F) -> io::Result<()>
+	where F :Fn(&FullConfig, &Matcher, &Request) -> String
+{
+	let mut line = String::new();
+	BufReader::new(&stream).read_line(&mut line)?;
+	let request :Request = serde_json::from_str(&line)?;
+
+	let output = state.get().map(|(c, m)| answer(c, m, &request));
+	let mut json = serde_json::to_string(&Response { output })?;
+	json.push('\n');
+	(&stream).write_all(json.as_bytes())
+}
+
+/** Listens for preexec requests until killed, answering them with the `answer` function
+
+Clients are served one at a time, which is enough for the shells of one user
+*/
+pub fn serve<F> (answer :F) -> sv::Result<()>
+	where F :Fn(&FullConfig, &Matcher, &Request) -> String
+{
+	let path = terror! { file::daemon_socket() => |_| sv::Error::NoRuntimeDirF };
+	let listener = terror! { bind(&path) };
+	eprintln!("Listening on {}", path.to_string_lossy());
+
+	let mut state = State { loaded: None };
+	// A client that goes away or misbehaves only concerns itself
+	for stream in listener.incoming().flatten() {
+		let _ = stream.set_read_timeout(Some(CLIENT_TIMEOUT));
+		let _ = handle(stream, &mut state, &answer);
+	}
+	Ok(())
+}
+
+/// Asks the daemon, if it is running. `None` means the caller should do the work itself
+pub fn request (req :&Request) -> Option<String> {
+	let path = file::daemon_socket()?;
+	let mut stream = UnixStream::connect(path).ok()?;
+	stream.set_read_timeout(Some(CLIENT_TIMEOUT)).ok()?;
+	stream.set_write_timeout(Some(CLIENT_TIMEOUT)).ok()?;
+
+	let mut json = serde_json::to_string(req).ok()?;
+	json.push('\n');
+	stream.write_all(json.as_bytes()).ok()?;
+
+	let mut line = String::new();
+	BufReader::new(&stream).read_line(&mut line).ok()?;
+	let response :Response = serde_json::from_str(&line).ok()?;
+	response.output
+}
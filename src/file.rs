@@ -3,6 +3,7 @@
 Everything in this module returns `Option<T>` because getting the home dir can fail.
 */
 
+use crate::shell::Shell;
 use std::path::PathBuf;
 use dirs::*;
 
@@ -17,7 +18,8 @@ pub enum Location {
 /// The type of data file
 pub enum FileType {
 	Config,
-	Aliases,
+	/// There is one aliases file per shell
+	Aliases(Shell),
 }
 
 /// Project name, for the folder
@@ -26,8 +28,31 @@ const NAME :&str = "switchable";
 const DOT_DIR :&str = ".switchable";
 /// Configuration file name
 const CONFIG_NAME :&str = "config.toml";
-/// Aliases file name
-const ALIAS_NAME :&str = "aliases.bash";
+/// Aliases file name, without the shell extension
+const ALIAS_NAME :&str = "aliases";
+/// Socket file name of the daemon
+const SOCKET_NAME :&str = "switchable.sock";
+/// Directory of the shims, in our data directory
+const SHIMS_DIR :&str = "bin";
+/// Cache file name for the compiled patterns
+const MATCHER_CACHE_NAME :&str = "matcher.json";
+/// bash-preexec file name in the home directory
+const PREEXEC_DOT_NAME :&str = ".bash-preexec.sh";
+/// Known system-wide locations of bash-preexec, in order of preference
+const PREEXEC_PATHS :&[&str] = &[
+	"/usr/share/bash-preexec/bash-preexec.sh",
+	"/usr/share/doc/bash-preexec/bash-preexec.sh",
+	"/usr/local/share/bash-preexec/bash-preexec.sh",
+	"/usr/lib/bash-preexec/bash-preexec.sh",
+	"/etc/profile.d/bash-preexec.sh",
+	"/usr/local/etc/profile.d/bash-preexec.sh", // Homebrew
+	"/opt/homebrew/etc/profile.d/bash-preexec.sh", // Homebrew on Apple Silicon
+];
+
+/// Aliases file name for the shell, eg. `aliases.bash`
+fn alias_name (sh :Shell) -> String {
+	format!("{}.{}", ALIAS_NAME, sh.name())
+}
 
 /// Get file path for a file in the specified location
 pub fn get_path (l :Location, name :FileType) -> Option<PathBuf> {	
@@ -42,8 +67,8 @@ pub fn get_xdg_path (name :FileType) -> Option<PathBuf> {
 	match name {
 		FileType::Config =>
 			config_dir().map(|v| v.join(NAME).join(CONFIG_NAME)),
-		FileType::Aliases =>
-			data_dir().map(|v| v.join(NAME).join(ALIAS_NAME)),
+		FileType::Aliases(sh) =>
+			data_dir().map(|v| v.join(NAME).join(alias_name(sh))),
 	}
 }
 
@@ -52,8 +77,8 @@ pub fn get_dot_path (name :FileType) -> Option<PathBuf> {
 	match name {
 		FileType::Config =>
 			home_dir().map(|v| v.join(DOT_DIR).join(CONFIG_NAME)),
-		FileType::Aliases =>
-			home_dir().map(|v| v.join(DOT_DIR).join(ALIAS_NAME)),
+		FileType::Aliases(sh) =>
+			home_dir().map(|v| v.join(DOT_DIR).join(alias_name(sh))),
 	}
 }
 
@@ -98,9 +123,9 @@ pub fn find_config_file () -> Option<PathBuf> {
 	find_file(FileType::Config)
 }
 
-/// Get aliases file path
-pub fn find_aliases_file () -> Option<PathBuf> {
-	find_file(FileType::Aliases)
+/// Get aliases file path for the shell
+pub fn find_aliases_file (sh :Shell) -> Option<PathBuf> {
+	find_file(FileType::Aliases(sh))
 }
 
 /** Get configuration file path with metadata about the location
@@ -113,3 +138,33 @@ pub fn find_config_file_meta () -> Option<(PathBuf, Location)> {
 		_ => None,
 	}
 }
+
+/** Known locations of bash-preexec, in order of preference
+
+The home directory one comes first. It is omitted if the home directory can't be found.
+*/
+pub fn bash_preexec_paths () -> Vec<PathBuf> {
+	home_dir().map(|v| v.join(PREEXEC_DOT_NAME)).into_iter()
+		.chain(PREEXEC_PATHS.iter().map(PathBuf::from))
+		.collect()
+}
+
+/// Returns the first existing bash-preexec file from the known locations
+pub fn find_bash_preexec () -> Option<PathBuf> {
+	bash_preexec_paths().into_iter().find(|v| v.exists())
+}
+
+/// Where the daemon listens, in the XDG runtime directory which only the user can access
+pub fn daemon_socket () -> Option<PathBuf> {
+	runtime_dir().map(|v| v.join(SOCKET_NAME))
+}
+
+/// Where `switchable shims` writes the shims, in the XDG data directory
+pub fn shims_dir () -> Option<PathBuf> {
+	data_dir().map(|v| v.join(NAME).join(SHIMS_DIR))
+}
+
+/// Where the patterns of the configuration are cached, in the XDG cache directory
+pub fn matcher_cache_file () -> Option<PathBuf> {
+	cache_dir().map(|v| v.join(NAME).join(MATCHER_CACHE_NAME))
+}
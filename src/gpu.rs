@@ -0,0 +1,277 @@
+/*! Discovering the GPUs from sysfs
+
+Each `class/drm/cardN` entry is a GPU, and its `device` link points to the PCI device.
+The `class/drm/renderDN` entries are the render nodes of the same devices.
+
+The sysfs root is configurable so that this can be run against a fixture tree.
+*/
+
+use std::{fs, io};
+use std::path::{Path, PathBuf};
+
+/// Where sysfs is usually mounted
+pub const SYSFS_ROOT :&str = "/sys";
+/// Where the DRM devices are listed, relative to the sysfs root
+const DRM_DIR :&str = "class/drm";
+/// Where the device nodes are
+const DEV_DRI :&str = "/dev/dri";
+
+/// A GPU as seen by the kernel
+#[derive(Clone, Debug)]
+pub struct Gpu {
+	/// N in cardN
+	pub card :u32,
+	/// PCI slot, eg. `0000:01:00.0`
+	pub slot :String,
+	pub vendor :u16,
+	pub device :u16,
+	/// Kernel driver name, eg. `amdgpu`
+	pub driver :Option<String>,
+	/// Whether the firmware used it for the boot display, which usually means it's integrated
+	pub boot_vga :bool,
+	/// eg. `/dev/dri/card0`
+	pub card_node :PathBuf,
+	/// eg. `/dev/dri/renderD128`
+	pub render_node :Option<PathBuf>,
+}
+
+impl Gpu {
+	/// The slot in the format understood by `DRI_PRIME`, eg. `pci-0000_01_00_0`
+	pub fn pci_tag (&self) -> String {
+		format!("pci-{}", self.slot.replace([':', '.'], "_"))
+	}
+
+	/// The `vendor:device` pair, eg. `1002:687f`
+	pub fn vendor_device (&self) -> String {
+		format!("{:04x}:{:04x}", self.vendor, self.device)
+	}
+}
+
+/// Parse a sysfs hexadecimal id like `0x10de`
+fn read_hex (path :&Path) -> io::Result<u16> {
+	let s = fs::read_to_string(path)?;
+	u16::from_str_radix(s.trim().trim_start_matches("0x"), 16)
+		.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Name of the file a symlink points to, eg. the driver name for `device/driver`
+fn link_name (path :&Path) -> Option<String> {
+	fs::read_link(path).ok()
+		.and_then(|v| v.file_name().map(|v| v.to_string_lossy().into_owned()))
+}
+
+/// PCI slot of a `device` directory, from its uevent or the name of the link
+fn read_slot (device :&Path) -> Option<String> {
+	let from_uevent = fs::read_to_string(device.join("uevent")).ok()
+		.and_then(|v| v.lines()
+			.find_map(|l| l.strip_prefix("PCI_SLOT_NAME=").map(str::to_string)));
+	from_uevent.or_else(|| link_name(device))
+}
+
+/// Read the GPU of a `cardN` directory, without its render node
+fn read_card (card :u32, path :&Path) -> io::Result<Gpu> {
+	let device = path.join("device");
+
+	// Only VGA devices have this file
+	let boot_vga = fs::read_to_string(device.join("boot_vga"))
+		.map(|v| v.trim() == "1")
+		.unwrap_or(false);
+
+	Ok(Gpu {
+		card,
+		slot: read_slot(&device).unwrap_or_default(),
+		vendor: read_hex(&device.join("vendor"))?,
+		device: read_hex(&device.join("device"))?,
+		driver: link_name(&device.join("driver")),
+		boot_vga,
+		card_node: Path::new(DEV_DRI).join(format!("card{}", card)),
+		render_node: None,
+	})
+}
+
+/// Returns the GPUs found under the sysfs root, sorted by card number. Cards whose PCI ids
+/// can't be read are skipped
+pub fn list (root :&Path) -> io::Result<Vec<Gpu>> {
+	let mut gpus = Vec::new();
+	// (render node name, PCI slot)
+	let mut renders :Vec<(String, String)> = Vec::new();
+
+	for entry in fs::read_dir(root.join(DRM_DIR))? {
+		let entry = entry?;
+		let name = entry.file_name().to_string_lossy().into_owned();
+
+		if name.starts_with("renderD") {
+			if let Some(slot) = read_slot(&entry.path().join("device")) {
+				renders.push((name, slot));
+			}
+			continue;
+		}
+
+		// Skip connectors like card0-HDMI-A-1
+		let card = match name.strip_prefix("card").map(str::parse::<u32>) {
+			Some(Ok(v)) => v,
+			_ => continue,
+		};
+		// Cards without a PCI device, like simpledrm or DisplayLink ones, aren't GPUs we can use
+		if let Ok(gpu) = read_card(card, &entry.path()) {
+			gpus.push(gpu);
+		}
+	}
+
+	// Match the render nodes to their card
+	for gpu in &mut gpus {
+		gpu.render_node = renders.iter()
+			.find(|(_, slot)| *slot == gpu.slot)
+			.map(|(name, _)| Path::new(DEV_DRI).join(name));
+	}
+
+	gpus.sort_by_key(|v| v.card);
+	Ok(gpus)
+}
+
+/// The GPU used for the boot display
+pub fn integrated (gpus :&[Gpu]) -> Option<&Gpu> {
+	gpus.iter().find(|v| v.boot_vga)
+}
+
+/// The first GPU that isn't used for the boot display, if there are several
+pub fn discrete (gpus :&[Gpu]) -> Option<&Gpu> {
+	if gpus.len() < 2 {
+		return None;
+	}
+	gpus.iter().find(|v| !v.boot_vga)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::os::unix::fs::symlink;
+
+	/// A sysfs tree in a temporary directory, removed when dropped
+	struct Fixture {
+		root :PathBuf,
+	}
+
+	impl Fixture {
+		fn new (name :&str) -> Fixture {
+			let root = std::env::temp_dir().join(format!("switchable-gpu-{}-{}", name, std::process::id()));
+			let _ = fs::remove_dir_all(&root);
+			fs::create_dir_all(root.join(DRM_DIR)).unwrap();
+			Fixture { root }
+		}
+
+		/// Adds a PCI device, with a driver, and whether it is used for the boot display
+		fn pci (&self, slot :&str, vendor :&str, device :&str, driver :&str, boot_vga :bool) -> &Self {
+			let dir = self.root.join("devices/pci0000:00").join(slot);
+			fs::create_dir_all(&dir).unwrap();
+			fs::write(dir.join("vendor"), format!("0x{}\n", vendor)).unwrap();
+			fs::write(dir.join("device"), format!("0x{}\n", device)).unwrap();
+			fs::write(dir.join("boot_vga"), if boot_vga { "1\n" } else { "0\n" }).unwrap();
+			fs::write(dir.join("uevent"), format!("DRIVER={}\nPCI_SLOT_NAME={}\n", driver, slot)).unwrap();
+			let drivers = self.root.join("bus/pci/drivers").join(driver);
+			fs::create_dir_all(&drivers).unwrap();
+			symlink(&drivers, dir.join("driver")).unwrap();
+			self
+		}
+
+		/// Adds a device that isn't on PCI, like the one of simpledrm
+		fn platform (&self, name :&str) -> &Self {
+			let dir = self.root.join("devices/platform").join(name);
+			fs::create_dir_all(&dir).unwrap();
+			fs::write(dir.join("uevent"), "DRIVER=simple-framebuffer\n").unwrap();
+			self
+		}
+
+		/// Adds a `class/drm` entry, eg. `card0` or `renderD128`, whose device is at `device`
+		/// under the devices directory
+		fn drm (&self, name :&str, device :&str) -> &Self {
+			let dir = self.root.join(DRM_DIR).join(name);
+			fs::create_dir_all(&dir).unwrap();
+			symlink(self.root.join("devices").join(device), dir.join("device")).unwrap();
+			self
+		}
+
+		/// Adds a connector, which has no device
+		fn connector (&self, name :&str) -> &Self {
+			fs::create_dir_all(self.root.join(DRM_DIR).join(name)).unwrap();
+			self
+		}
+	}
+
+	impl Drop for Fixture {
+		fn drop (&mut self) {
+			let _ = fs::remove_dir_all(&self.root);
+		}
+	}
+
+	/// An Intel GPU for the boot display, and an AMD one
+	fn hybrid (name :&str) -> Fixture {
+		let fx = Fixture::new(name);
+		fx.pci("0000:00:02.0", "8086", "3e9b", "i915", true)
+			.pci("0000:01:00.0", "1002", "687f", "amdgpu", false)
+			.drm("card0", "pci0000:00/0000:00:02.0")
+			.drm("card1", "pci0000:00/0000:01:00.0")
+			.drm("renderD128", "pci0000:00/0000:00:02.0")
+			.drm("renderD129", "pci0000:00/0000:01:00.0")
+			.connector("card0-eDP-1")
+			.connector("card1-HDMI-A-1");
+		fx
+	}
+
+	#[test]
+	fn cards_and_render_nodes () {
+		let fx = hybrid("hybrid");
+		let gpus = list(&fx.root).unwrap();
+		assert_eq!(gpus.len(), 2);
+
+		let intel = &gpus[0];
+		assert_eq!(intel.card, 0);
+		assert_eq!(intel.slot, "0000:00:02.0");
+		assert_eq!(intel.pci_tag(), "pci-0000_00_02_0");
+		assert_eq!(intel.vendor_device(), "8086:3e9b");
+		assert_eq!(intel.driver.as_deref(), Some("i915"));
+		assert!(intel.boot_vga);
+		assert_eq!(intel.card_node, Path::new("/dev/dri/card0"));
+		assert_eq!(intel.render_node.as_deref(), Some(Path::new("/dev/dri/renderD128")));
+
+		let amd = &gpus[1];
+		assert_eq!(amd.card, 1);
+		assert_eq!(amd.vendor_device(), "1002:687f");
+		assert_eq!(amd.driver.as_deref(), Some("amdgpu"));
+		assert!(!amd.boot_vga);
+		assert_eq!(amd.render_node.as_deref(), Some(Path::new("/dev/dri/renderD129")));
+
+		assert_eq!(integrated(&gpus).map(|v| v.card), Some(0));
+		assert_eq!(discrete(&gpus).map(|v| v.card), Some(1));
+	}
+
+	#[test]
+	fn cards_without_pci_ids_are_skipped () {
+		let fx = hybrid("non-pci");
+		fx.platform("simple-framebuffer.0")
+			.drm("card2", "platform/simple-framebuffer.0")
+			.connector("card2-Unknown-1");
+		let gpus = list(&fx.root).unwrap();
+		assert_eq!(gpus.iter().map(|v| v.card).collect::<Vec<_>>(), [0, 1]);
+	}
+
+	#[test]
+	fn single_gpu_is_not_discrete () {
+		let fx = Fixture::new("single");
+		fx.pci("0000:00:02.0", "8086", "3e9b", "i915", true)
+			.drm("card0", "pci0000:00/0000:00:02.0")
+			.connector("card0-eDP-1");
+		let gpus = list(&fx.root).unwrap();
+		assert_eq!(gpus.len(), 1);
+		assert_eq!(gpus[0].render_node, None);
+		assert!(integrated(&gpus).is_some());
+		assert!(discrete(&gpus).is_none());
+	}
+
+	#[test]
+	fn missing_drm_directory_fails () {
+		let fx = Fixture::new("missing");
+		fs::remove_dir_all(fx.root.join(DRM_DIR)).unwrap();
+		assert!(list(&fx.root).is_err());
+	}
+}
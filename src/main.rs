@@ -6,6 +6,16 @@ mod config;
 mod alias;
 mod file;
 mod app;
+mod shell;
+mod backend;
+mod gpu;
+mod rule;
+mod cmdline;
+mod prefix;
+mod matcher;
+mod daemon;
+mod session;
+mod shim;
 
 prelude!();
 use std::env;
@@ -23,10 +33,17 @@ r#"Usage:
   {p_name} --help | --version
 
 Subcommands:
+  init            Print the shell code to load in your shell's rc file
   run             Enable the GPU for the supplied command
   reload-aliases  Reloads the aliases if possible
   show-config     Displays the loaded configuration
-  xrandr          List DRI_PRIME values for each GPU
+  which           Explain which rule matches a command, and what it changes
+  gpus            List the GPUs and their 'driver' values
+  daemon          Keep the configuration loaded for the preexec hook
+  off, on         Disable or enable offloading in this shell
+  set-driver      Use another driver in this shell
+  status          Show the state of this shell
+  shims           Write wrappers offloading the commands started outside of the shell
 "#,
 	p_name = p_name);
 }
@@ -47,10 +64,18 @@ fn main () {
 	let args: Vec<String> = env::args().collect();
 	let program_name = args[0].clone();
 	
+	// Invoked through a link named after a command, run that command
+	if let Some(command) = shim::link_name(&program_name) {
+		let shim_args = ["_shim", command].iter().map(|v| v.to_string())
+			.chain(args[1..].iter().cloned())
+			.collect();
+		exit(commands::execute(&program_name, shim_args));
+	}
+	
 	// Handle command line arguments
 	let parser = create_parser();
 	let opts = tear! { parser.parse(&args[1..]) => |f :getopts::Fail| {
-		eprint!("{}", f.to_string());
+		eprint!("{}", f);
 		exit(exitcode::BAD_ARG);
 	}};
 	
@@ -0,0 +1,381 @@
+/*! The patterns of the configuration, compiled once
+
+Preexec runs for every command, so the patterns are compiled into one `RegexSet` per subject
+(see `rule::Subject`) instead of one regex at a time. Invalid patterns are left out, and
+reported with where they are and why they failed.
+
+Compiling them is what takes time when preexec loads the configuration. The daemon keeps the
+`Matcher` of the loaded configuration, and without it, preexec uses `Matcher::load`: the invalid
+patterns and what the patterns made of the programs and arguments already seen are cached in
+`~/.cache/switchable/matcher.json`, keyed by the stamp of the configuration file, so that the
+patterns are only compiled for commands that aren't in the cache.
+*/
+
+prelude!();
+use crate::{file, VERSION};
+use crate::config::{FullConfig, Stamp};
+use crate::rule::{Kind, Subject};
+use crate::util::Lookup;
+use regex::{Regex, RegexSet};
+use serde::{Serialize, Deserialize};
+use std::cell::{OnceCell, RefCell};
+use std::collections::HashMap;
+use std::{fmt, fs, path::Path};
+
+/// How many results the cache keeps, before starting over
+const CACHE_SIZE :usize = 1000;
+
+/// Which key of a rule a pattern comes from
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Field {
+	Match,
+	Exclude,
+	Args,
+}
+
+impl Field {
+	/// Name as written in the config
+	pub fn name (self) -> &'static str {
+		match self {
+			Field::Match => "match",
+			Field::Exclude => "exclude",
+			Field::Args => "args",
+		}
+	}
+}
+
+/// Where a pattern comes from, ordered like in the configuration
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Origin {
+	/// Index in `FullConfig::rules`, or `None` for the top-level `exclude` key
+	pub rule :Option<usize>,
+	pub field :Field,
+	/// Index in the list of patterns
+	pub index :usize,
+}
+
+/// A pattern that failed to compile
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InvalidPattern {
+	pub origin :Origin,
+	pub pattern :String,
+	pub error :String,
+}
+
+impl InvalidPattern {
+	/// Where it is, eg. `match[1] of rule 2`, where rules are counted from the `match` key
+	pub fn location (&self) -> String {
+		let o = &self.origin;
+		match o.rule {
+			Some(r) => format!("{}[{}] of rule {}", o.field.name(), o.index, r + 1),
+			None => format!("top-level {}[{}]", o.field.name(), o.index),
+		}
+	}
+}
+
+impl fmt::Display for InvalidPattern {
+	fn fmt (&self, f :&mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{} `{}`: {}", self.location(), self.pattern, self.error)
+	}
+}
+
+/// The regex sources of the patterns
+#[derive(Default)]
+struct Sources {
+	/// Patterns on the program, with their subject, as written and as a regex
+	program :Vec<(Origin, Subject, String, String)>,
+	/// Patterns on the arguments, as written and as a regex
+	args :Vec<(Origin, String, String)>,
+}
+
+impl Sources {
+	/// Translate every pattern of the configuration
+	fn new (config :&FullConfig) -> Sources {
+		let mut sources = Sources::default();
+		let mut add = |origin :Origin, kind :Kind, pattern :&String| {
+			let source = kind.regex_source(pattern);
+			if origin.field == Field::Args {
+				sources.args.push((origin, pattern.clone(), source));
+			} else {
+				sources.program.push((origin, kind.subject(pattern), pattern.clone(), source));
+			}
+		};
+
+		for (i, p) in config.exclude.iter().enumerate() {
+			add(Origin { rule: None, field: Field::Exclude, index: i }, Kind::Glob, p);
+		}
+		for (r, rule) in config.rules.iter().enumerate() {
+			let fields = [(Field::Match, &rule.match_), (Field::Exclude, &rule.exclude), (Field::Args, &rule.args)];
+			for (field, patterns) in fields.iter() {
+				for (i, p) in patterns.iter().enumerate() {
+					add(Origin { rule: Some(r), field: *field, index: i }, rule.kind, p);
+				}
+			}
+		}
+
+		sources
+	}
+	
+	/// Leaves out the invalid patterns
+	fn without (mut self, invalid :&[InvalidPattern]) -> Sources {
+		let valid = |o :&Origin| invalid.iter().all(|v| v.origin != *o);
+		self.program.retain(|(o, ..)| valid(o));
+		self.args.retain(|(o, ..)| valid(o));
+		self
+	}
+}
+
+/// One set of program patterns
+struct ProgramSet {
+	subject :Subject,
+	set :RegexSet,
+	origins :Vec<Origin>,
+}
+
+/// The compiled patterns
+struct Compiled {
+	sets :Vec<ProgramSet>,
+	args :HashMap<Origin, Regex>,
+}
+
+impl Compiled {
+	/// Compiles the sources, each pattern once when they are all valid. Each set of program
+	/// patterns is compiled at once, and only when that fails are its patterns compiled alone
+	/// to find the invalid ones. The set may still fail to compile without them if it is too
+	/// big, in which case all of its patterns are reported as invalid. Regexes with a space
+	/// are invalid too, since they can't match a program
+	fn new (sources :&Sources) -> (Compiled, Vec<InvalidPattern>) {
+		let mut invalid = Vec::new();
+		let mut sets = Vec::new();
+		for &subject in Subject::ALL {
+			let mut patterns :Vec<(Origin, &String, &String)> = sources.program.iter()
+				.filter(|(_, s, _, _)| *s == subject)
+				.map(|(o, _, p, re)| (*o, p, re))
+				.collect();
+			if subject == Subject::Word {
+				// The program is a single word, unlike the whole command line `match` used to see
+				patterns.retain(|(origin, pattern, _)| !pattern.contains(' ') || {
+					let error = "regexes only see the program, use a rule with `program` and `args` to match its arguments";
+					invalid.push(InvalidPattern { origin: *origin, pattern: pattern.to_string(), error: error.to_string() });
+					false
+				});
+			}
+			if patterns.is_empty() {
+				continue;
+			}
+			
+			let mut set = RegexSet::new(patterns.iter().map(|(_, _, re)| re));
+			if set.is_err() {
+				patterns.retain(|(origin, pattern, re)| match Regex::new(re) {
+					Ok(_) => true,
+					Err(e) => {
+						invalid.push(InvalidPattern { origin: *origin, pattern: pattern.to_string(), error: e.to_string() });
+						false
+					},
+				});
+				set = RegexSet::new(patterns.iter().map(|(_, _, re)| re));
+			}
+			match set {
+				Ok(set) => sets.push(ProgramSet { subject, set, origins: patterns.iter().map(|(o, _, _)| *o).collect() }),
+				Err(e) => invalid.extend(patterns.into_iter().map(|(origin, pattern, _)| {
+					InvalidPattern { origin, pattern: pattern.clone(), error: e.to_string() }
+				})),
+			}
+		}
+		
+		let mut args = HashMap::new();
+		for (origin, pattern, re) in &sources.args {
+			match Regex::new(re) {
+				Ok(re) => { args.insert(*origin, re); },
+				Err(e) => invalid.push(InvalidPattern { origin: *origin, pattern: pattern.clone(), error: e.to_string() }),
+			}
+		}
+		invalid.sort_by_key(|v| v.origin);
+
+		(Compiled { sets, args }, invalid)
+	}
+	
+	/// The patterns of the subject matching its value
+	fn program_matches (&self, subject :Subject, value :&str) -> Vec<Origin> {
+		self.sets.iter()
+			.filter(|s| s.subject == subject)
+			.flat_map(|s| s.set.matches(value).into_iter().map(move |i| s.origins[i]))
+			.collect()
+	}
+}
+
+/// The cache file contents
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+	/// Version of switchable that wrote it
+	version :String,
+	stamp :Stamp,
+	invalid :Vec<InvalidPattern>,
+	/// The patterns matching the value of a subject
+	programs :Vec<(Subject, String, Vec<Origin>)>,
+	/// Whether the pattern of `args` matches the argument
+	args :Vec<(Origin, String, bool)>,
+}
+
+/// What the patterns made of the programs and arguments seen, for a version of the configuration
+struct Cache {
+	stamp :Stamp,
+	programs :HashMap<(Subject, String), Vec<Origin>>,
+	args :HashMap<(Origin, String), bool>,
+	/// Whether there are new results to write
+	changed :bool,
+}
+
+impl Cache {
+	/// Makes room for a new result
+	fn make_room (&mut self) {
+		if self.programs.len() + self.args.len() >= CACHE_SIZE {
+			self.programs.clear();
+			self.args.clear();
+		}
+		self.changed = true;
+	}
+}
+
+/// The patterns of a configuration, compiled when they are needed
+pub struct Matcher {
+	sources :Sources,
+	compiled :OnceCell<Compiled>,
+	/// The patterns that failed to compile, and are never matched, in the order of the configuration
+	pub invalid :Vec<InvalidPattern>,
+	/// The results of the cache file, with the new ones, for `load`
+	cache :Option<RefCell<Cache>>,
+}
+
+impl Matcher {
+	/// Compiles the patterns of the configuration
+	pub fn new (config :&FullConfig) -> Matcher {
+		let sources = Sources::new(config);
+		let (compiled, invalid) = Compiled::new(&sources);
+		Matcher { sources, compiled: OnceCell::from(compiled), invalid, cache: None }
+	}
+	
+	/// The patterns of the configuration, with the results cached for its version. They are only
+	/// compiled when the cache is out of date or a result is missing. See `save`
+	pub fn load (config :&FullConfig) -> Matcher {
+		let stamp = match &config.stamp {
+			Some(v) => v,
+			None => return Matcher::new(config),
+		};
+		
+		let cached = file::matcher_cache_file()
+			.and_then(|p| fs::read_to_string(p).ok())
+			.and_then(|s| serde_json::from_str::<CacheFile>(&s).ok())
+			.filter(|c| c.version == VERSION && c.stamp == *stamp);
+		match cached {
+			Some(c) => Matcher {
+				sources: Sources::new(config).without(&c.invalid),
+				compiled: OnceCell::new(),
+				invalid: c.invalid,
+				cache: Some(RefCell::new(Cache {
+					stamp: c.stamp,
+					programs: c.programs.into_iter().map(|(s, v, o)| ((s, v), o)).collect(),
+					args: c.args.into_iter().map(|(o, a, m)| ((o, a), m)).collect(),
+					changed: false,
+				})),
+			},
+			None => {
+				let mut matcher = Matcher::new(config);
+				let cache = Cache { stamp: stamp.clone(), programs: HashMap::new(), args: HashMap::new(), changed: true };
+				matcher.cache = Some(RefCell::new(cache));
+				matcher
+			},
+		}
+	}
+	
+	/// Writes the results of `load` to the cache file if there are new ones
+	pub fn save (&self) {
+		let cache = tear! { self.cache.as_ref().map(RefCell::borrow) };
+		let path = tear! { file::matcher_cache_file() };
+		if cache.changed {
+			write_cache(&path, &CacheFile {
+				version: VERSION.to_string(),
+				stamp: cache.stamp.clone(),
+				invalid: self.invalid.clone(),
+				programs: cache.programs.iter().map(|((s, v), o)| (*s, v.clone(), o.clone())).collect(),
+				args: cache.args.iter().map(|((o, a), m)| (*o, a.clone(), *m)).collect(),
+			});
+		}
+	}
+	
+	/// The compiled patterns, compiling them if needed
+	fn compiled (&self) -> &Compiled {
+		self.compiled.get_or_init(|| Compiled::new(&self.sources).0)
+	}
+
+	/// Where the patterns matching the program `argv[0]` come from, with its path looked up
+	/// with `lookup`
+	pub fn program_matches (&self, argv0 :&str, lookup :&Lookup) -> Vec<Origin> {
+		let mut origins = Vec::new();
+		for &subject in Subject::ALL {
+			if self.sources.program.iter().all(|(_, s, ..)| *s != subject) {
+				continue;
+			}
+			let value = subject.of(argv0, lookup);
+			let hits = match &self.cache {
+				Some(cache) => {
+					let key = (subject, value.into_owned());
+					let cached = cache.borrow().programs.get(&key).cloned();
+					cached.unwrap_or_else(|| {
+						let hits = self.compiled().program_matches(subject, &key.1);
+						let mut cache = cache.borrow_mut();
+						cache.make_room();
+						cache.programs.insert(key, hits.clone());
+						hits
+					})
+				},
+				None => self.compiled().program_matches(subject, &value),
+			};
+			origins.extend(hits);
+		}
+		origins
+	}
+
+	/// Whether the pattern of `args` matches the argument. Invalid patterns never match
+	pub fn arg_matches (&self, rule :usize, index :usize, arg :&str) -> bool {
+		let origin = Origin { rule: Some(rule), field: Field::Args, index };
+		let matches = || self.compiled().args.get(&origin).is_some_and(|re| re.is_match(arg));
+		match &self.cache {
+			Some(cache) => {
+				let key = (origin, arg.to_string());
+				let cached = cache.borrow().args.get(&key).copied();
+				cached.unwrap_or_else(|| {
+					let m = matches();
+					let mut cache = cache.borrow_mut();
+					cache.make_room();
+					cache.args.insert(key, m);
+					m
+				})
+			},
+			None => matches(),
+		}
+	}
+
+	/// The error of the pattern, if it is invalid
+	pub fn error (&self, origin :Origin) -> Option<&str> {
+		self.invalid.iter()
+			.find(|v| v.origin == origin)
+			.map(|v| v.error.as_str())
+	}
+}
+
+/// Writes the cache through a temporary file, so that concurrent shells don't read half of it.
+/// Errors are ignored, as the cache is only an optimization
+fn write_cache (path :&Path, cache :&CacheFile) {
+	let json = match serde_json::to_string(cache) {
+		Ok(v) => v,
+		Err(_) => return,
+	};
+	let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+	let written = path.parent().map_or(Ok(()), fs::create_dir_all)
+		.and_then(|_| fs::write(&tmp, json))
+		.and_then(|_| fs::rename(&tmp, path));
+	if written.is_err() {
+		let _ = fs::remove_file(&tmp);
+	}
+}
@@ -0,0 +1,201 @@
+/*! Commands that run another command, which are skipped when matching
+
+In `FOO=1 sudo -u me nice -n5 steam`, the program that matters is `steam`. The leading
+variable assignments are always skipped, then the prefix commands with their options.
+
+```toml
+prefix = [
+    "nohup",
+    { command = "nice", value_options = [ "-n", "--adjustment" ] },
+    { command = "env", value_options = [ "-u", "--unset" ], assignments = true },
+]
+```
+
+Options are parsed like getopt does: `-n5`, `-n 5` and `--adjustment=5` all work,
+and `--` ends the options.
+*/
+
+prelude!();
+use serde::Deserialize;
+
+/// The prefixes used when the `prefix` key isn't set: command, options taking a value,
+/// whether `VAR=value` words may come before the command
+const DEFAULT_PREFIXES :&[(&str, &[&str], bool)] = &[
+	("sudo", &["-u", "--user", "-g", "--group", "-h", "--host", "-p", "--prompt", "-C",
+		"--close-from", "-D", "--chdir", "-r", "--role", "-t", "--type", "-U", "--other-user",
+		"-T", "--command-timeout"], true),
+	("doas", &["-u", "-C"], false),
+	("env", &["-u", "--unset", "-C", "--chdir"], true),
+	("nice", &["-n", "--adjustment"], false),
+	("ionice", &["-c", "--class", "-n", "--classdata"], false),
+	("time", &["-f", "--format", "-o", "--output"], false),
+	("stdbuf", &["-i", "--input", "-o", "--output", "-e", "--error"], false),
+	("exec", &["-a"], false),
+	("command", &[], false),
+	("nohup", &[], false),
+	("setsid", &[], false),
+];
+
+/// An entry of the `prefix` key
+#[derive(Deserialize, Clone, Debug)]
+#[serde(from = "RawPrefix")]
+pub struct Prefix {
+	pub command :String,
+	/// Options that take a value, eg. `-n` for nice
+	pub value_options :Vec<String>,
+	/// Whether `VAR=value` words may come before the command, like with env
+	pub assignments :bool,
+}
+
+/// What TOML gives us for a `prefix` entry
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawPrefix {
+	Command(String),
+	Table {
+		command :String,
+		#[serde(default)]
+		value_options :Vec<String>,
+		#[serde(default)]
+		assignments :bool,
+	},
+}
+
+impl From<RawPrefix> for Prefix {
+	fn from (v :RawPrefix) -> Self {
+		match v {
+			RawPrefix::Command(command) =>
+				Prefix { command, value_options: Vec::new(), assignments: false },
+			RawPrefix::Table { command, value_options, assignments } =>
+				Prefix { command, value_options, assignments },
+		}
+	}
+}
+
+impl Prefix {
+	/// Whether the option takes a value
+	fn takes_value (&self, option :&str) -> bool {
+		self.value_options.iter().any(|o| o == option)
+	}
+
+	/// Number of words taken by the options after the command, and the assignments if allowed
+	fn skip_options (&self, args :&[String]) -> usize {
+		let mut i = 0;
+		while let Some(arg) = args.get(i) {
+			i += 1;
+			if arg == "--" {
+				break;
+			} else if arg.starts_with("--") {
+				// Long option, with its value after `=` or in the next word
+				if !arg.contains('=') && self.takes_value(arg) {
+					i += 1;
+				}
+			} else if arg.len() > 1 && arg.starts_with('-') {
+				// Short options, the first one taking a value takes the rest of the word
+				let opts = &arg[1..];
+				let value_at = opts.char_indices()
+					.find(|&(_, c)| self.takes_value(&format!("-{}", c)))
+					.map(|(j, c)| j + c.len_utf8());
+				if value_at == Some(opts.len()) {
+					i += 1;
+				}
+			} else if self.assignments && is_assignment(arg) {
+				// Nothing more to skip
+			} else {
+				i -= 1;
+				break;
+			}
+		}
+		i.min(args.len())
+	}
+}
+
+/// The prefixes used when the `prefix` key isn't set
+pub fn defaults () -> Vec<Prefix> {
+	DEFAULT_PREFIXES.iter()
+		.map(|(command, opts, assignments)| Prefix {
+			command: command.to_string(),
+			value_options: opts.iter().map(|o| o.to_string()).collect(),
+			assignments: *assignments,
+		})
+		.collect()
+}
+
+/// Whether the word is a variable assignment like `FOO=1`
+pub fn is_assignment (word :&str) -> bool {
+	let name = match word.split_once('=') {
+		Some((n, _)) => n.strip_suffix('+').unwrap_or(n), // `FOO+=1`
+		None => return false,
+	};
+	let mut chars = name.chars();
+	chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+		&& chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The last path component, so that `/usr/bin/sudo` is `sudo`
+pub fn basename (program :&str) -> &str {
+	program.rsplit('/').next().unwrap_or(program)
+}
+
+/** The words of the command that is really run, without the leading assignments and prefixes
+
+```rust
+strip(&["FOO=1", "nice", "-n5", "steam", "-silent"], &prefix::defaults());
+// ["steam", "-silent"]
+```
+*/
+pub fn strip<'a> (words :&'a [String], prefixes :&[Prefix]) -> &'a [String] {
+	let assignments = words.iter().take_while(|w| is_assignment(w)).count();
+	let mut words = &words[assignments..];
+
+	while let Some(program) = words.first() {
+		let prefix = tear! { prefixes.iter().find(|p| p.command == basename(program)) => |_| words };
+		let args = &words[1..];
+		words = &args[prefix.skip_options(args)..];
+	}
+	words
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// The words left by `strip` with the default prefixes
+	fn stripped (line :&str) -> Vec<String> {
+		let words :Vec<String> = line.split(' ').map(String::from).collect();
+		strip(&words, &defaults()).to_vec()
+	}
+
+	#[test]
+	fn assignments_and_options () {
+		assert_eq!(stripped("FOO=1 nice -n5 steam"), ["steam"]);
+		assert_eq!(stripped("nice -n 5 steam -silent"), ["steam", "-silent"]);
+		assert_eq!(stripped("nice --adjustment=5 /usr/bin/steam"), ["/usr/bin/steam"]);
+	}
+
+	#[test]
+	fn grouped_short_options () {
+		assert_eq!(stripped("sudo -iu me steam"), ["steam"]);
+		assert_eq!(stripped("sudo -E -u me time -p steam"), ["steam"]);
+	}
+
+	#[test]
+	fn assignments_after_prefix () {
+		assert_eq!(stripped("env -u X A=1 steam"), ["steam"]);
+		// nice doesn't take assignments, so `A=1` is its command
+		assert_eq!(stripped("nice A=1 steam"), ["A=1", "steam"]);
+	}
+
+	#[test]
+	fn end_of_options () {
+		assert_eq!(stripped("nice -- steam"), ["steam"]);
+		assert_eq!(stripped("env -- -steam"), ["-steam"]);
+	}
+
+	#[test]
+	fn no_command () {
+		assert!(stripped("nice -n5").is_empty());
+		assert!(stripped("sudo -u").is_empty());
+		assert!(stripped("FOO=1").is_empty());
+	}
+}
@@ -0,0 +1,401 @@
+/*! Rules deciding which commands are offloaded, and how
+
+```toml
+[[rule]]
+name = "games"
+match = [ "steam", "lutris" ]
+driver = "discrete"
+env = { MESA_VK_DEVICE_SELECT = "1002:687f" }
+unset = [ "VK_ICD_FILENAMES" ]
+
+[[rule]]
+program = "python"
+args = [ "train.py" ]
+
+[[rule]]
+kind = "glob"
+match = [ "*.x86_64", "~/games/bin/steam*" ]
+exclude = [ "~/games/bin/steamcmd*" ]
+```
+
+`match` is matched against the program of each command, while `program` and `args` look at
+the whole command: the program is compared by name, or by path if it has a slash, and each
+pattern of `args` must match the argument at the same position.
+
+The patterns of `match` and `args` are of the rule's `kind`:
+- `literal`, the default: the whole word, eg. `steam` doesn't match `steamcmd`
+- `glob`: shell patterns with `*`, `?` and `[...]`, matching the whole word
+- `regex`: regexes matching anywhere in the word
+
+Literals and globs without a slash are matched against the name of the program, and those
+with one against its path, with `~` expanded.
+
+`exclude` is matched like `match`, and vetoes the rule for the programs it matches.
+The top-level `exclude` key is a list of globs vetoing every rule.
+
+When several rules match, the one with the highest `priority` wins (0 by default). Among
+those, rules using the integrated GPU, with `driver = "integrated"` or `driver = 0`, win over
+the others, so that commands can be kept off the discrete GPU when it is the default. Remaining
+ties go to the first rule in the configuration, or the last one with `strategy = "last-match"`.
+
+With `precedence = "user"`, at the top level or in a rule, the variables already set in the
+environment, like an exported `DRI_PRIME`, keep their value. With `"config"`, the default,
+the rule overrides them.
+
+The top-level `match` key is the same as a rule with only `match` of the `regex` kind,
+as it always was.
+*/
+
+use crate::config::Driver;
+use crate::prefix;
+use crate::util::Lookup;
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::borrow::Cow;
+use std::{fs, path::Path};
+
+/// Name of the rule made from the top-level `match` key
+pub const MATCH_KEY_NAME :&str = "match";
+
+/// How the patterns of a rule are written
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Kind {
+	#[default]
+	Literal,
+	Glob,
+	Regex,
+}
+
+impl Kind {
+	/// Name as written in the config
+	pub fn name (self) -> &'static str {
+		match self {
+			Kind::Literal => "literal",
+			Kind::Glob => "glob",
+			Kind::Regex => "regex",
+		}
+	}
+	
+	/// The source of the regex equivalent to the pattern
+	pub fn regex_source (self, pattern :&str) -> String {
+		let home = dirs::home_dir();
+		match self {
+			Kind::Literal => format!("^{}$", regex::escape(&expand_tilde(pattern, home.as_deref()))),
+			Kind::Glob => glob_to_regex(&expand_tilde(pattern, home.as_deref())),
+			Kind::Regex => pattern.to_string(),
+		}
+	}
+	
+	/// What a pattern is matched against for the program `argv[0]`: its name, its path if
+	/// the pattern has a slash, or the word as is for regexes
+	pub fn subject (self, pattern :&str) -> Subject {
+		match self {
+			Kind::Regex => Subject::Word,
+			_ if !pattern.contains('/') => Subject::Name,
+			_ => Subject::Path,
+		}
+	}
+}
+
+/// What a pattern on the program is matched against
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Subject {
+	/// The word as typed
+	Word,
+	/// The last component of the word
+	Name,
+	/// Where the program is, after searching PATH
+	Path,
+}
+
+impl Subject {
+	/// All of them
+	pub const ALL :&'static [Subject] = &[Subject::Word, Subject::Name, Subject::Path];
+	
+	/// The subject of the program `argv[0]`, looked up like the shell would
+	pub fn of<'a> (self, argv0 :&'a str, lookup :&Lookup) -> Cow<'a, str> {
+		match self {
+			Subject::Word => Cow::Borrowed(argv0),
+			Subject::Name => Cow::Borrowed(prefix::basename(argv0)),
+			Subject::Path => match lookup.find(&expand_tilde(argv0, lookup.home.as_deref())) {
+				Some(p) => Cow::Owned(p.to_string_lossy().into_owned()),
+				None => Cow::Borrowed(argv0),
+			},
+		}
+	}
+}
+
+/// Replaces a leading `~/` with the home directory
+fn expand_tilde<'a> (s :&'a str, home :Option<&Path>) -> Cow<'a, str> {
+	match (s.strip_prefix("~/"), home) {
+		(Some(rest), Some(home)) => Cow::Owned(home.join(rest).to_string_lossy().into_owned()),
+		_ => Cow::Borrowed(s),
+	}
+}
+
+/// Translates a shell pattern into an anchored regex. Like in `case`, `*` also matches `/`,
+/// a `]` right after `[` or `[!` is part of the bracket expression, and a `[` that isn't closed
+/// is literal
+fn glob_to_regex (glob :&str) -> String {
+	let chars :Vec<char> = glob.chars().collect();
+	let mut re = String::from("^");
+	let mut i = 0;
+	while let Some(&c) = chars.get(i) {
+		i += 1;
+		match c {
+			'*' => re.push_str(".*"),
+			'?' => re.push('.'),
+			'[' => {
+				let negated = matches!(chars.get(i), Some('!') | Some('^'));
+				let first = if negated { i + 1 } else { i };
+				// The first character is never the closing bracket
+				let end = chars.get(first + 1..)
+					.and_then(|rest| rest.iter().position(|&c| c == ']'))
+					.map(|p| first + 1 + p);
+				let end = match end {
+					Some(end) => end,
+					None => {
+						re.push_str("\\[");
+						continue;
+					},
+				};
+				
+				// Characters and ranges, escaped, so that the regex crate doesn't read nested classes
+				// or set operations like `&&`, `--` and `~~`. Reversed ranges match nothing
+				let mut class = String::new();
+				let mut j = first;
+				while j < end {
+					let c = chars[j];
+					if j + 2 < end && chars[j + 1] == '-' {
+						let last = chars[j + 2];
+						if c <= last {
+							class.push_str(&format!("{}-{}", class_char(c), class_char(last)));
+						}
+						j += 3;
+					} else {
+						class.push_str(&class_char(c));
+						j += 1;
+					}
+				}
+				match (class.is_empty(), negated) {
+					(true, false) => re.push_str("\\b\\B"),
+					(true, true) => re.push_str("(?s:.)"),
+					(false, false) => re.push_str(&format!("[{}]", class)),
+					(false, true) => re.push_str(&format!("[^{}]", class)),
+				}
+				i = end + 1;
+			},
+			'\\' => if let Some(&c) = chars.get(i) {
+				i += 1;
+				re.push_str(&regex::escape(&c.to_string()));
+			},
+			c => re.push_str(&regex::escape(&c.to_string())),
+		}
+	}
+	re.push('$');
+	re
+}
+
+/// A character in a regex class, escaped if it means something there
+fn class_char (c :char) -> String {
+	match c {
+		'\\' | '[' | ']' | '&' | '~' | '-' | '^' => format!("\\{}", c),
+		c => c.to_string(),
+	}
+}
+
+/// The `strategy` key: which rule wins among matching rules of the same priority
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+	#[default]
+	FirstMatch,
+	LastMatch,
+}
+
+impl Strategy {
+	/// Name as written in the config
+	pub fn name (self) -> &'static str {
+		match self {
+			Strategy::FirstMatch => "first-match",
+			Strategy::LastMatch => "last-match",
+		}
+	}
+}
+
+/// The `precedence` key: whether a variable already set in the environment keeps its value
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Precedence {
+	/// The configuration overrides the environment
+	#[default]
+	Config,
+	/// Variables set in the environment are left as they are
+	User,
+}
+
+impl Precedence {
+	/// Name as written in the config
+	pub fn name (self) -> &'static str {
+		match self {
+			Precedence::Config => "config",
+			Precedence::User => "user",
+		}
+	}
+}
+
+/// A `[[rule]]` table
+#[derive(Deserialize, Clone, Debug)]
+pub struct Rule {
+	pub name :Option<String>,
+	/// How `match` and `args` are written
+	#[serde(default)]
+	pub kind :Kind,
+	/// Patterns to match programs against
+	#[serde(rename = "match", default)]
+	pub match_ :Vec<String>,
+	/// Name or path of the program to match
+	pub program :Option<String>,
+	/// Patterns the first arguments of `program` must match, in order
+	#[serde(default)]
+	pub args :Vec<String>,
+	/// Patterns of programs the rule doesn't match, even if `match` or `program` does
+	#[serde(default)]
+	pub exclude :Vec<String>,
+	/// Which GPU to use, defaults to the `driver` key
+	pub driver :Option<Driver>,
+	/// Rules with a higher priority win when several match
+	#[serde(default)]
+	pub priority :i64,
+	/// Whether the environment wins over the rule, defaults to the `precedence` key
+	pub precedence :Option<Precedence>,
+	/// Extra variables to set
+	#[serde(default)]
+	pub env :BTreeMap<String, String>,
+	/// Variables to unset
+	#[serde(default)]
+	pub unset :Vec<String>,
+	#[serde(default = "enabled_default")]
+	pub enabled :bool,
+}
+
+/// Rules are enabled unless stated otherwise
+fn enabled_default () -> bool {
+	true
+}
+
+impl Rule {
+	/// The rule made from the top-level `match` key
+	pub fn from_match_key (match_ :Vec<String>) -> Rule {
+		Rule {
+			name: Some(MATCH_KEY_NAME.to_string()),
+			kind: Kind::Regex,
+			match_,
+			program: None,
+			args: Vec::new(),
+			exclude: Vec::new(),
+			driver: None,
+			priority: 0,
+			precedence: None,
+			env: BTreeMap::new(),
+			unset: Vec::new(),
+			enabled: true,
+		}
+	}
+
+	/// The GPU to use, with the `driver` key as default
+	pub fn driver<'a> (&'a self, default :&'a Driver) -> &'a Driver {
+		self.driver.as_ref().unwrap_or(default)
+	}
+
+	/// Whether the environment wins, with the `precedence` key as default
+	pub fn precedence (&self, default :Precedence) -> Precedence {
+		self.precedence.unwrap_or(default)
+	}
+
+	/// Name to display, with the index among the `[[rule]]` tables as fallback
+	pub fn display_name (&self, index :usize) -> String {
+		match &self.name {
+			Some(n) => n.clone(),
+			None => format!("rule #{}", index + 1),
+		}
+	}
+}
+
+/// Whether `argv[0]` is the program, by name or by path, looked up like the shell would
+pub fn is_program (expected :&str, argv0 :&str, lookup :&Lookup) -> bool {
+	if !expected.contains('/') {
+		return prefix::basename(argv0) == expected;
+	}
+	
+	// Compare the paths, after following symlinks when possible
+	let resolve = |p :&Path| fs::canonicalize(p).unwrap_or_else(|_| p.to_path_buf());
+	match lookup.find(argv0) {
+		Some(found) => resolve(&found) == resolve(Path::new(expected)),
+		None => argv0 == expected,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use regex::Regex;
+
+	/// Whether the glob matches the text
+	fn glob_matches (glob :&str, text :&str) -> bool {
+		Regex::new(&glob_to_regex(glob)).unwrap().is_match(text)
+	}
+
+	#[test]
+	fn wildcards () {
+		assert!(glob_matches("steam*", "steam-runtime"));
+		assert!(glob_matches("~/games/*", "~/games/a/b"));
+		assert!(glob_matches("a?c", "abc"));
+		assert!(!glob_matches("a?c", "ac"));
+		assert!(glob_matches("a.b", "a.b"));
+		assert!(!glob_matches("a.b", "axb"));
+		assert!(glob_matches(r"a\*", "a*"));
+		assert!(!glob_matches(r"a\*", "ab"));
+	}
+
+	#[test]
+	fn brackets () {
+		assert!(glob_matches("[a-c]x", "bx"));
+		assert!(!glob_matches("[!a-c]x", "bx"));
+		assert!(glob_matches("[^a-c]x", "dx"));
+		assert!(glob_matches("[-a]", "-"));
+	}
+
+	#[test]
+	fn leading_bracket_is_literal () {
+		assert!(glob_matches("[]a]", "]"));
+		assert!(glob_matches("[]a]", "a"));
+		assert!(!glob_matches("[!]a]", "]"));
+		assert!(glob_matches("[!]a]", "b"));
+	}
+
+	#[test]
+	fn unclosed_bracket_is_literal () {
+		assert!(glob_matches("[abc", "[abc"));
+		assert!(!glob_matches("[abc", "a"));
+		assert!(glob_matches("x[]", "x[]"));
+		assert!(glob_matches("[[z", "[[z"));
+	}
+
+	#[test]
+	fn set_operators_are_literal () {
+		for glob in ["[a&&b]", "[a--b]", "[a~~b]", "[[:x]", "[z-a]", "[!z-a]"] {
+			assert!(Regex::new(&glob_to_regex(glob)).is_ok(), "{}", glob);
+		}
+		assert!(glob_matches("[a&&b]", "&"));
+		assert!(glob_matches("[!+--]", "#"));
+		assert!(!glob_matches("[!+--]", ","));
+		assert!(!glob_matches("[!--]", "-"));
+		assert!(glob_matches("[a--b]", "b"));
+		assert!(!glob_matches("[a--b]", "-"));
+		assert!(!glob_matches("[z-a]", "m"));
+		assert!(glob_matches("[a~~b]", "~"));
+		assert!(glob_matches("[[:x]", ":"));
+	}
+}
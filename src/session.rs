@@ -0,0 +1,124 @@
+/*! The state of one shell, changed by `switchable off`, `on` and `set-driver`
+
+Like `reload-aliases`, these commands are handled by the preexec hook, which prints the shell
+code changing the state, as a program can't change the shell that runs it. The state is kept
+in exported variables, so that preexec and the daemon can see it. The shells started from
+this one inherit it.
+*/
+
+use crate::config::{self, Driver, FullConfig};
+use crate::backend::Backend;
+use crate::commands::INIT_NAME;
+use crate::rule::Rule;
+use crate::shell::Shell;
+use crate::{cmdline, gpu, prefix};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Set when offloading is off in the shell
+pub const OFF_VAR :&str = "SWITCHABLE_OFF";
+/// The driver used instead of the configured ones
+pub const DRIVER_VAR :&str = "SWITCHABLE_DRIVER";
+
+/// The state of a shell
+#[derive(Default, Debug)]
+pub struct Session {
+	/// Whether preexec changes nothing
+	pub off :bool,
+	/// The driver used by every rule, instead of their own and the `driver` key
+	pub driver :Option<Driver>,
+}
+
+impl Session {
+	/// The state recorded in the environment of the shell
+	pub fn from_env (env :&BTreeMap<String, String>) -> Session {
+		Session {
+			off: env.contains_key(OFF_VAR),
+			driver: env.get(DRIVER_VAR).map(|d| Driver::parse(d)),
+		}
+	}
+
+	/// The GPU to use for the rule, with the `driver` key as default
+	pub fn driver<'a> (&'a self, rule :&'a Rule, default :&'a Driver) -> &'a Driver {
+		self.driver.as_ref().unwrap_or_else(|| rule.driver(default))
+	}
+}
+
+/// A command changing the state
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Change {
+	Off,
+	On,
+	/// `None` goes back to the configured drivers
+	SetDriver(Option<String>),
+}
+
+impl Change {
+	/// The change made by the subcommand with these arguments, if they are valid
+	pub fn from_args (subcommand :&str, args :&[String]) -> Option<Change> {
+		match (subcommand, args) {
+			("off", []) => Some(Change::Off),
+			("on", []) => Some(Change::On),
+			("set-driver", [d]) if d == "--reset" => Some(Change::SetDriver(None)),
+			("set-driver", [d]) if !d.starts_with('-') => Some(Change::SetDriver(Some(d.clone()))),
+			_ => None,
+		}
+	}
+
+	/// The changes made by the commands of the command line, in order
+	pub fn from_line (line :&str) -> Vec<Change> {
+		cmdline::split(line).iter()
+			.filter_map(|c| match prefix::strip(&c.words, &[]) {
+				[program, sub, args @ ..] if prefix::basename(program) == INIT_NAME =>
+					Change::from_args(sub, args),
+				_ => None,
+			})
+			.collect()
+	}
+
+	/// Resolves the driver of `set-driver` like the configuration would, or with the default
+	/// backend without one, so that values it can't use are refused before they are exported
+	pub fn check (&self, config :Option<&FullConfig>) -> config::Result<()> {
+		let driver = match self {
+			Change::SetDriver(Some(d)) => Driver::parse(d),
+			_ => return Ok(()),
+		};
+		let resolved = match config {
+			Some(c) => driver.resolve(&*c.backend, &c.sysfs),
+			None => driver.resolve(&*Backend::Auto.resolve(), Path::new(gpu::SYSFS_ROOT)),
+		};
+		resolved.map(|_| ())
+	}
+
+	/// Makes the change in the environment
+	pub fn apply (&self, env :&mut BTreeMap<String, String>) {
+		match self {
+			Change::Off => { env.insert(OFF_VAR.to_string(), "1".to_string()); },
+			Change::On => { env.remove(OFF_VAR); },
+			Change::SetDriver(Some(d)) => { env.insert(DRIVER_VAR.to_string(), d.clone()); },
+			Change::SetDriver(None) => { env.remove(DRIVER_VAR); },
+		}
+	}
+
+	/// Whether the environment already has the change
+	pub fn is_applied (&self, env :&BTreeMap<String, String>) -> bool {
+		match self {
+			Change::Off => env.contains_key(OFF_VAR),
+			Change::On => !env.contains_key(OFF_VAR),
+			Change::SetDriver(d) => env.get(DRIVER_VAR) == d.as_ref(),
+		}
+	}
+
+	/// Shell code making the change in the shell, and telling so
+	pub fn code (&self, sh :Shell) -> String {
+		let (statement, msg) = match self {
+			Change::Off => (sh.export(OFF_VAR, "1"), "Offloading is off in this shell".to_string()),
+			Change::On => (sh.unset(OFF_VAR), "Offloading is on in this shell".to_string()),
+			Change::SetDriver(Some(d)) => (sh.export(DRIVER_VAR, d),
+				format!("Commands matched in this shell use driver {}", d)),
+			Change::SetDriver(None) => (sh.unset(DRIVER_VAR),
+				"Commands matched in this shell use their configured driver".to_string()),
+		};
+		format!("{}\n{}\n", statement, sh.echo(&msg))
+	}
+}
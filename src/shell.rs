@@ -0,0 +1,119 @@
+/*! Supported shells
+
+The shell is chosen when running `switchable init <shell>`, and then remembered in the
+`SWITCHABLE_SHELL` environment variable so that the hooks know which syntax to output.
+*/
+
+use crate::util;
+use std::{env, fmt, str::FromStr};
+
+/// Environment variable that remembers the shell passed to init
+pub const SHELL_VAR :&str = "SWITCHABLE_SHELL";
+
+/// A shell we can generate code for
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Shell {
+	Bash,
+	Zsh,
+	Fish,
+}
+
+impl Shell {
+	/// All supported shells, for help texts
+	pub const ALL :&'static [Shell] = &[Shell::Bash, Shell::Zsh, Shell::Fish];
+
+	/// Name of the shell, also used as the aliases file extension
+	pub fn name (self) -> &'static str {
+		match self {
+			Shell::Bash => "bash",
+			Shell::Zsh => "zsh",
+			Shell::Fish => "fish",
+		}
+	}
+	
+	/// Whether the shell understands POSIX syntax (export, unset, aliases…)
+	pub fn is_posix (self) -> bool {
+		match self {
+			Shell::Bash | Shell::Zsh => true,
+			Shell::Fish => false,
+		}
+	}
+	
+	/// Quote a string so that it is a single word for this shell
+	pub fn escape (self, s :&str) -> String {
+		if self.is_posix() {
+			util::shell_escape(s)
+		} else {
+			util::fish_escape(s)
+		}
+	}
+	
+	/// Quote the string only if it contains special characters
+	pub fn word (self, s :&str) -> String {
+		let is_plain = |c :char| c.is_ascii_alphanumeric() || "_-+=.,:/@%".contains(c);
+		if !s.is_empty() && s.chars().all(is_plain) {
+			s.to_string()
+		} else {
+			self.escape(s)
+		}
+	}
+	
+	/// Statement exporting the variable with the (unescaped) value
+	pub fn export (self, var :&str, value :&str) -> String {
+		if self.is_posix() {
+			format!("export {}={}", var, self.escape(value))
+		} else {
+			format!("set -gx {} {}", var, self.escape(value))
+		}
+	}
+	
+	/// Statement unsetting the variable
+	pub fn unset (self, var :&str) -> String {
+		if self.is_posix() {
+			format!("unset {}", var)
+		} else {
+			format!("set -e {}", var)
+		}
+	}
+	
+	/// Statement printing the message
+	pub fn echo (self, msg :&str) -> String {
+		format!("echo {}", self.escape(msg))
+	}
+	
+	/// Statement printing the message to stderr
+	pub fn echo_err (self, msg :&str) -> String {
+		format!("echo {} >&2", self.escape(msg))
+	}
+	
+	/// Statement loading the file in the current shell
+	pub fn source (self, path :&str) -> String {
+		format!("source {}", self.escape(path))
+	}
+
+	/// The shell set by init, defaults to bash
+	pub fn from_env () -> Shell {
+		env::var(SHELL_VAR).ok()
+			.and_then(|v| v.parse().ok())
+			.unwrap_or(Shell::Bash)
+	}
+}
+
+impl FromStr for Shell {
+	type Err = String;
+
+	fn from_str (s :&str) -> Result<Self, Self::Err> {
+		match s {
+			"bash" => Ok(Shell::Bash),
+			"zsh" => Ok(Shell::Zsh),
+			"fish" => Ok(Shell::Fish),
+			v => Err(format!("Unsupported shell \"{}\"", v)),
+		}
+	}
+}
+
+impl fmt::Display for Shell {
+	fn fmt (&self, f :&mut fmt::Formatter) -> fmt::Result {
+		f.write_str(self.name())
+	}
+}
@@ -0,0 +1,209 @@
+/*! Wrappers for the configured commands, so that they are offloaded outside of the shell too
+
+`switchable shims` writes a small script in `~/.local/share/switchable/bin` for each command
+named by the rules and the `alias` key. With that directory first in PATH, programs started
+from a desktop menu or a script run the shim, which runs `switchable _shim <command> <args>`.
+That finds the rule for the command like preexec does, and runs the real program, the next
+one in PATH, with the rule's variables.
+
+Only patterns that are plain names get a shim, eg. `steam` but not `steam*` or `~/bin/steam`.
+
+With `switchable shims --link`, the shims are symlinks to switchable instead of scripts.
+Invoked through a link named after a command, switchable runs that command like `_shim` does,
+see `link_name`.
+*/
+
+prelude!();
+use crate::config::{self, FullConfig};
+use crate::rule::{Kind, Precedence};
+use crate::backend::Offload;
+use crate::matcher::Matcher;
+use crate::session::Session;
+use crate::commands::INIT_NAME;
+use crate::{app, file, prefix, util};
+use crate::util::Lookup;
+use std::collections::BTreeMap;
+use std::{env, fs, path::{Path, PathBuf}};
+
+/// Second line of the shims, which tells them apart from other files
+const MARKER :&str = "# Generated by switchable shims, modifications will be overwritten";
+
+/// Module for `write`
+pub mod ws {
+	prelude!();
+	use std::{io, result, path::PathBuf};
+
+	#[derive(ThisError, Debug)]
+	pub enum Error {
+		#[error("Failed to find the shims directory because the home directory could not be determined")]
+		FindDirF,
+		#[error("Failed to find the path of the switchable executable")]
+		CurrentExeF(#[source] io::Error),
+		#[error("Failed to write shim {0:?}")]
+		WriteF(PathBuf, #[source] io::Error),
+		#[error("Failed to remove old shim {0:?}")]
+		RemoveF(PathBuf, #[source] io::Error),
+	}
+
+	pub type Result<T> = result::Result<T, self::Error>;
+}
+
+/// What `write` did
+pub struct Written {
+	pub dir :PathBuf,
+	/// The commands with a shim
+	pub written :Vec<String>,
+	/// The commands whose shim was removed, as they aren't configured anymore
+	pub removed :Vec<String>,
+	/// The commands with a file in the directory that isn't a shim, which is left alone
+	pub skipped :Vec<String>,
+}
+
+/// The pattern as a command name, if it only matches that name
+fn plain_name (kind :Kind, pattern :&str) -> Option<&str> {
+	let name = match kind {
+		Kind::Literal => pattern,
+		Kind::Glob if !pattern.contains(|c| "*?[\\".contains(c)) => pattern,
+		Kind::Glob => return None,
+		Kind::Regex => {
+			let name = pattern.strip_prefix('^').unwrap_or(pattern);
+			let name = name.strip_suffix('$').unwrap_or(name);
+			tear_if! { regex::escape(name) != name, None }
+			name
+		},
+	};
+	let valid = !name.is_empty() && name != "." && name != ".." && !name.contains('/');
+	if valid { Some(name) } else { None }
+}
+
+/// The commands that get a shim: the names in the enabled rules, and the aliased commands
+pub fn commands (config :&FullConfig) -> Vec<String> {
+	let mut names :Vec<String> = Vec::new();
+	for rule in config.rules.iter().filter(|r| r.enabled) {
+		let patterns = rule.match_.iter().filter_map(|p| plain_name(rule.kind, p));
+		let program = rule.program.as_deref().filter(|p| !p.contains('/'));
+		names.extend(patterns.chain(program).map(String::from));
+	}
+	names.extend(config.alias_commands());
+	names.sort();
+	names.dedup();
+	names
+}
+
+/// Whether the file is a shim we wrote, a script or a link to `exe`
+fn is_shim (path :&Path, exe :&Path) -> bool {
+	let is_link = path.symlink_metadata().is_ok_and(|m| m.file_type().is_symlink());
+	if is_link {
+		fs::canonicalize(path).ok().as_deref() == Some(exe)
+	} else {
+		fs::read_to_string(path).is_ok_and(|s| s.lines().nth(1) == Some(MARKER))
+	}
+}
+
+/// Writes the shims of the configured commands, as symlinks to switchable if `link`, and removes
+/// those of the commands that aren't configured anymore
+pub fn write (config :&FullConfig, link :bool) -> ws::Result<Written> {
+	use std::os::unix::fs::{symlink, PermissionsExt};
+	use ws::Error::*;
+
+	let dir = terror! { file::shims_dir() => |_| FindDirF };
+	let exe = terror! { env::current_exe().and_then(fs::canonicalize) => CurrentExeF };
+	terror! { fs::create_dir_all(&dir) => |e| WriteF(dir, e) };
+
+	let commands = commands(config);
+	let mut written = Written { dir: dir.clone(), written: Vec::new(), removed: Vec::new(), skipped: Vec::new() };
+
+	// Remove the shims of the commands that aren't configured anymore
+	let entries = terror! { fs::read_dir(&dir) => |e| WriteF(dir, e) };
+	for entry in entries.flatten() {
+		let name = entry.file_name().to_string_lossy().into_owned();
+		let path = entry.path();
+		if !commands.contains(&name) && is_shim(&path, &exe) {
+			terror! { fs::remove_file(&path) => |e| RemoveF(path, e) };
+			written.removed.push(name);
+		}
+	}
+
+	for command in commands {
+		let path = dir.join(&command);
+		if path.symlink_metadata().is_ok() {
+			if !is_shim(&path, &exe) {
+				written.skipped.push(command);
+				continue;
+			}
+			terror! { fs::remove_file(&path) => |e| RemoveF(path, e) };
+		}
+
+		if link {
+			terror! { symlink(&exe, &path) => |e| WriteF(path, e) };
+		} else {
+			let script = format!("#!/bin/sh\n{}\nexec {} _shim {} \"$@\"\n",
+				MARKER, util::shell_escape(&exe.to_string_lossy()), util::shell_escape(&command));
+			terror! { fs::write(&path, script) => |e| WriteF(path, e) };
+			let perms = fs::Permissions::from_mode(0o755);
+			terror! { fs::set_permissions(&path, perms) => |e| WriteF(path, e) };
+		}
+		written.written.push(command);
+	}
+
+	Ok(written)
+}
+
+/** What to change to run the command from a shim, in the environment `env`
+
+The rules are matched like preexec does, then the commands of the `alias` key use their driver.
+`None` if nothing should change.
+*/
+pub fn offload (config :&FullConfig, matcher :&Matcher, command :&str, args :&[String],
+	env :&BTreeMap<String, String>) -> Option<config::Result<Offload>>
+{
+	let session = Session::from_env(env);
+	tear_if! { session.off, None }
+
+	let line :Vec<String> = std::iter::once(command).chain(args.iter().map(String::as_str))
+		.map(util::shell_escape)
+		.collect();
+	let matches = app::matches_command(config, matcher, &Lookup::current(), &line.join(" "));
+	if matches.winner().is_some() {
+		return matches.offload(config, env);
+	}
+
+	let alias = config.alias.iter().find(|a| a.command() == command)?;
+	let driver = session.driver.as_ref().or_else(|| alias.driver()).unwrap_or(&config.driver);
+	Some(config.driver_offload(driver).map(|mut offload| {
+		if config.precedence == Precedence::User {
+			offload.keep_env(env);
+		}
+		offload
+	}))
+}
+
+/** The command switchable is invoked as through a link, eg. `steam` for
+`~/.local/share/switchable/bin/steam -> switchable`
+
+It is `None` when the name in `argv[0]` is the one of the executable or `switchable`, and
+when it isn't a command that gets a shim, unless there is a link with that name in the shims
+directory. Links with other names, eg. `sw -> switchable`, work like switchable itself.
+*/
+pub fn link_name (argv0 :&str) -> Option<&str> {
+	let name = prefix::basename(argv0);
+	let exe = env::current_exe().and_then(fs::canonicalize).ok()?;
+	let exe_name = exe.file_name()?.to_str()?;
+	tear_if! { name == exe_name || name == INIT_NAME || name.is_empty(), None }
+	
+	let in_shims = file::shims_dir()
+		.and_then(|d| fs::canonicalize(d.join(name)).ok())
+		.is_some_and(|p| p == exe);
+	let configured = || config::load_config().is_ok_and(|c| commands(&c).iter().any(|v| v == name));
+	if in_shims || configured() { Some(name) } else { None }
+}
+
+/// The program the shim of the command runs: the first one in PATH that isn't a shim, or a
+/// link to switchable elsewhere
+pub fn find_real (command :&str) -> Option<PathBuf> {
+	let exe = env::current_exe().and_then(fs::canonicalize).ok();
+	let lookup = Lookup::current();
+	let found = lookup.executables(command)
+		.find(|p| exe.is_none() || fs::canonicalize(p).ok() != exe);
+	found
+}
@@ -1,6 +1,7 @@
 //! Syntax and type extensions
 
 /// Represent data, and its metadata
+#[derive(Debug)]
 pub struct Metadata<Meta, Data>
 (
 	pub Meta,
@@ -9,18 +10,6 @@ pub struct Metadata<Meta, Data>
 
 // Extensions for normal types
 
-/// Add the reduce method to iterators
-pub trait IterExt<T> {
-	// T: iterator value
-	fn reduce (self, f :impl FnMut(T, T) -> T) -> Option<T>;
-}
-
-impl<T, Me> IterExt<T> for Me where Me :Iterator<Item=T> {
-	fn reduce (mut self, f :impl FnMut(T, T) -> T) -> Option<T> {
-		self.next().map(|first| self.fold(first, f))
-	}
-}
-
 /// Split an `Option<(_, _)>` into two
 pub trait Split2<T1, T2> {
 	fn split2 (self) -> (Option<T1>, Option<T2>);
@@ -1,4 +1,8 @@
 //! Actual utility functions that go nowhere else, and aren't syntax extensions (`slang`)
+use std::{env, fs};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
 
 /// Given two Vecs, returns their set difference as two Vecs
 pub fn set_diff<T :Ord> (mut left :Vec<T>, mut right :Vec<T>) -> (Vec<T>, Vec<T>) {
@@ -48,3 +52,85 @@ pub fn set_diff<T :Ord> (mut left :Vec<T>, mut right :Vec<T>) -> (Vec<T>, Vec<T>
 pub fn shell_escape (s :&str) -> String {
 	format!("'{}'", s.replace("'", r"'\''"))
 }
+
+/// Single quotes a string for fish, where backslashes and quotes are escaped inside quotes
+pub fn fish_escape (s :&str) -> String {
+	format!("'{}'", s.replace('\\', r"\\").replace('\'', r"\'"))
+}
+
+/// Whether the file is an executable
+fn is_exe (p :&Path) -> bool {
+	use std::os::unix::fs::PermissionsExt;
+	p.metadata().is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+/** Where the shell looks up the programs of a command line: its PATH, working directory
+and home directory
+
+The daemon uses those of the client, which sends them, rather than its own.
+*/
+#[derive(Clone, Default, Debug)]
+pub struct Lookup {
+	pub path :Option<OsString>,
+	pub cwd :Option<PathBuf>,
+	pub home :Option<PathBuf>,
+}
+
+impl Lookup {
+	/// The ones of this process
+	pub fn current () -> Lookup {
+		Lookup {
+			path: env::var_os("PATH"),
+			cwd: env::current_dir().ok(),
+			home: dirs::home_dir(),
+		}
+	}
+	
+	/// The ones of a shell with the environment `env`, in the directory `cwd`
+	pub fn of_shell (env :&BTreeMap<String, String>, cwd :Option<PathBuf>) -> Lookup {
+		Lookup {
+			path: env.get("PATH").map(Into::into),
+			cwd,
+			home: env.get("HOME").map(Into::into),
+		}
+	}
+	
+	/// The path, relative to the working directory if it isn't absolute, without `.` components
+	pub fn absolute (&self, p :&Path) -> PathBuf {
+		let joined = match &self.cwd {
+			Some(cwd) => cwd.join(p),
+			None => p.to_path_buf(),
+		};
+		joined.components().filter(|c| *c != Component::CurDir).collect()
+	}
+	
+	/// The executables named `program` in the directories of PATH, in order, none if it is unset.
+	/// Our shims are skipped, so that the programs they run are found instead, and so are empty
+	/// entries, rather than searching the working directory
+	pub fn executables<'a> (&'a self, program :&'a str) -> impl Iterator<Item = PathBuf> + 'a {
+		let shims = crate::file::shims_dir().and_then(|d| fs::canonicalize(d).ok());
+		let dirs :Vec<_> = self.path.iter().flat_map(env::split_paths).collect();
+		dirs.into_iter()
+			.filter(|dir| !dir.as_os_str().is_empty())
+			.map(move |dir| self.absolute(&dir))
+			.filter(move |dir| shims.is_none() || fs::canonicalize(dir).ok() != shims)
+			.map(move |dir| dir.join(program))
+			.filter(|p| is_exe(p))
+	}
+	
+	/// Finds the executable the shell would run for the program, searching PATH if it has no slash
+	pub fn find (&self, program :&str) -> Option<PathBuf> {
+		if program.contains('/') {
+			let p = self.absolute(Path::new(program));
+			return if is_exe(&p) { Some(p) } else { None };
+		}
+		self.executables(program).next()
+	}
+}
+
+/// The environment variables of the process, without those that aren't utf8
+pub fn environment () -> BTreeMap<String, String> {
+	env::vars_os()
+		.filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)))
+		.collect()
+}